@@ -0,0 +1,442 @@
+//! Evaluation of `#if` conditions.
+//!
+//! A condition is a C integer constant expression, evaluated after `defined` is
+//! replaced and macros are expanded. `true` and `false` are `1` and `0`, and a
+//! condition holds if it evaluates to anything but `0`. Like in C, the operands
+//! that `&&`, `||` and `?:` skip are not evaluated, so `B != 0 && A / B > 2` doesn't
+//! fail when `B` is `0`. Errors point at the part of the line that the failing
+//! token was produced by.
+//!
+//! Conditions can also use string literals, which are compared with `==` and `!=`
+//! and passed to builtin functions like `contains(s, sub)` or `len(s)`. An identifier
+//! that a macro expanded to is a string where a string is expected, so with
+//! `#define BACKEND vulkan`, `BACKEND == "vulkan"` holds. Any other identifier is unknown. String literals are not
+//! expanded, and `str(NAME)` is replaced with the value of `NAME` as a string
+//! together with `defined`.
+
+use std::ops::Range;
+
+use crate::expand::Origin;
+use crate::{Error, ErrorKind, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Number(i64),
+    /// A string literal, without quotes and with escapes replaced
+    Str(String),
+    /// An identifier, and whether it was produced by a macro
+    Ident(bool),
+    Punct,
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: Kind,
+    text: String,
+    /// Byte offset in the condition
+    offset: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Int(i64),
+    Str(String),
+    /// An identifier that isn't a macro, see [Kind::Ident]
+    Word(Token),
+}
+
+/// Operators, longest first so that `<<` isn't read as two `<`.
+const PUNCTS: [&str; 25] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "(", ")", ",", "!", "~", "*", "/", "%", "+", "-", "<", ">", "&", "^", "|", "?", ":",
+];
+
+/// An expanded condition and where its parts come from.
+struct Condition<'c> {
+    text: &'c str,
+    origins: &'c [Origin],
+}
+
+impl Condition<'_> {
+    /// The bytes of the line that `len` bytes at `offset` in the condition were produced by.
+    fn span(&self, offset: usize, len: usize) -> Range<usize> {
+        let idx = self.origins.partition_point(|origin| origin.range.start <= offset);
+        let Some(origin) = idx.checked_sub(1).map(|idx| &self.origins[idx]) else {
+            return 0..0;
+        };
+
+        if offset >= origin.range.end {
+            return origin.span.end..origin.span.end;
+        }
+        if origin.range.len() == origin.span.len() {
+            let start = origin.span.start + offset - origin.range.start;
+            return start..start + len;
+        }
+        // Produced by a macro
+        return origin.span.clone();
+    }
+
+    /// Whether the byte `offset` in the condition was produced by a macro.
+    fn is_expanded(&self, offset: usize) -> bool {
+        let idx = self.origins.partition_point(|origin| origin.range.start <= offset);
+        return idx.checked_sub(1).is_some_and(|idx| self.origins[idx].expanded);
+    }
+
+    fn invalid(&self, offset: usize, len: usize, message: String) -> Error {
+        return Error::from(ErrorKind::InvalidCondition(self.text.trim().to_string(), message)).at(self.span(offset, len));
+    }
+}
+
+/// Evaluates the expanded condition of an `#if` or `#elif`. `origins` are the parts
+/// of the line every part of `condition` was produced by, see [expand_condition](crate::expand::expand_condition).
+pub(crate) fn evaluate(condition: &str, origins: &[Origin]) -> Result<bool> {
+    let condition = Condition { text: condition, origins };
+    let mut parser = Parser { tokens: tokenize(&condition)?, condition, pos: 0 };
+    let start = parser.peek().clone();
+    let value = parser.ternary(true)?;
+
+    let token = parser.next();
+    if token.kind != Kind::End {
+        return Err(parser.unexpected(&token));
+    }
+
+    return Ok(parser.int(value, &start)? != 0);
+}
+
+fn tokenize(source: &Condition) -> Result<Vec<Token>> {
+    let condition = source.text;
+    let mut tokens = vec![];
+    let mut chars = condition.char_indices().peekable();
+
+    while let Some(&(offset, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c.is_alphanumeric() || c == '_' {
+            let mut end = offset;
+            while let Some(&(idx, c)) = chars.peek()
+                && (c.is_alphanumeric() || c == '_' || c == '.')
+            {
+                end = idx + c.len_utf8();
+                chars.next();
+            }
+            let text = &condition[offset..end];
+            let kind = if c.is_ascii_digit() { Kind::Number(parse_number(source, text, offset)?) } else { Kind::Ident(source.is_expanded(offset)) };
+            tokens.push(Token { kind, text: text.to_string(), offset });
+            continue;
+        }
+
+        if c == '"' {
+            chars.next();
+            let unterminated = || source.invalid(offset, condition.len() - offset, "unterminated string".to_string());
+            let mut value = String::new();
+            let end = loop {
+                match chars.next().ok_or_else(unterminated)? {
+                    (idx, '"') => break idx + 1,
+                    (_, '\\') => match chars.next().ok_or_else(unterminated)? {
+                        (_, 'n') => value.push('\n'),
+                        (_, 't') => value.push('\t'),
+                        (_, c @ ('"' | '\\')) => value.push(c),
+                        (idx, c) => {
+                            return Err(source.invalid(idx - 1, 1 + c.len_utf8(), format!("unknown escape `\\{}`", c)));
+                        },
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            tokens.push(Token { kind: Kind::Str(value), text: condition[offset..end].to_string(), offset });
+            continue;
+        }
+
+        let Some(punct) = PUNCTS.iter().find(|punct| condition[offset..].starts_with(**punct)) else {
+            return Err(source.invalid(offset, c.len_utf8(), format!("unexpected character `{}`", c)));
+        };
+        for _ in 0..punct.len() {
+            chars.next();
+        }
+        tokens.push(Token { kind: Kind::Punct, text: punct.to_string(), offset });
+    }
+
+    tokens.push(Token { kind: Kind::End, text: String::new(), offset: condition.len() });
+
+    return Ok(tokens);
+}
+
+/// Parses a decimal, hexadecimal (`0x`), binary (`0b`) or octal (`0`) integer literal,
+/// with an optional `u`, `l`, `ul` or `ll` suffix.
+fn parse_number(source: &Condition, text: &str, offset: usize) -> Result<i64> {
+    let digits = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    let (digits, radix) = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        (bin, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (&digits[1..], 8)
+    } else {
+        (digits, 10)
+    };
+
+    if radix != 16 && (digits.contains(['.', 'e', 'E'])) {
+        return Err(source.invalid(offset, text.len(), format!("floating point number `{}` isn't allowed", text)));
+    }
+
+    return i64::from_str_radix(digits, radix)
+        .map_err(|_| source.invalid(offset, text.len(), format!("invalid number `{}`", text)));
+}
+
+/// The precedence of a binary operator, higher binds tighter.
+fn precedence(op: &str) -> Option<u8> {
+    return Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | "<=" | ">" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    });
+}
+
+struct Parser<'c> {
+    condition: Condition<'c>,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        return &self.tokens[self.pos];
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != Kind::End {
+            self.pos += 1;
+        }
+        return token;
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if self.peek().kind == Kind::Punct && self.peek().text == punct {
+            self.pos += 1;
+            return true;
+        }
+        return false;
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<()> {
+        if !self.eat(punct) {
+            let token = self.next();
+            return Err(self.error(&token, &format!("expected `{}`", punct)));
+        }
+        return Ok(());
+    }
+
+    fn error(&self, token: &Token, message: &str) -> Error {
+        if token.kind == Kind::End {
+            return self.condition.invalid(token.offset, 0, format!("{} at the end of the condition", message));
+        }
+        return self.condition.invalid(token.offset, token.text.len(), message.to_string());
+    }
+
+    fn unexpected(&self, token: &Token) -> Error {
+        if token.kind == Kind::End {
+            return self.error(token, "expected an expression");
+        }
+        return self.error(token, &format!("unexpected `{}`", token.text));
+    }
+
+    /// The value of the operand starting at `start` as an integer.
+    fn int(&self, value: Value, start: &Token) -> Result<i64> {
+        return match value {
+            Value::Int(value) => Ok(value),
+            Value::Str(_) => Err(self.error(start, "expected an integer, found a string")),
+            Value::Word(token) => Err(self.error(&token, &format!("unknown identifier `{}`", token.text))),
+        };
+    }
+
+    /// The value of the operand starting at `start` as a string.
+    fn string(&self, value: Value, start: &Token) -> Result<String> {
+        return match value {
+            Value::Int(_) => Err(self.error(start, "expected a string, found an integer")),
+            Value::Str(value) => Ok(value),
+            Value::Word(token) if token.kind == Kind::Ident(true) => Ok(token.text),
+            Value::Word(token) => Err(self.error(&token, &format!("unknown identifier `{}`", token.text))),
+        };
+    }
+
+    /// `cond ? a : b`. Operands are only evaluated if `eval` is set, otherwise `0`
+    /// is returned for them.
+    fn ternary(&mut self, eval: bool) -> Result<Value> {
+        let start = self.peek().clone();
+        let cond = self.binary(1, eval)?;
+        if !self.eat("?") {
+            return Ok(cond);
+        }
+
+        let cond = if eval { self.int(cond, &start)? != 0 } else { false };
+        let then = self.ternary(eval && cond)?;
+        self.expect(":")?;
+        let otherwise = self.ternary(eval && !cond)?;
+
+        return Ok(if cond { then } else { otherwise });
+    }
+
+    /// Binary operators with a precedence of at least `min_precedence`.
+    fn binary(&mut self, min_precedence: u8, eval: bool) -> Result<Value> {
+        let lhs_start = self.peek().clone();
+        let mut lhs = self.unary(eval)?;
+
+        while self.peek().kind == Kind::Punct
+            && let Some(prec) = precedence(&self.peek().text)
+            && prec >= min_precedence
+        {
+            let op = self.next();
+            let eval_rhs = match op.text.as_str() {
+                "&&" | "||" if eval => {
+                    let truthy = self.int(lhs.clone(), &lhs_start)? != 0;
+                    truthy == (op.text == "&&")
+                },
+                _ => eval,
+            };
+            let rhs_start = self.peek().clone();
+            let rhs = self.binary(prec + 1, eval_rhs)?;
+            lhs = if eval { self.apply(&op, (lhs, &lhs_start), (rhs, &rhs_start), eval_rhs)? } else { Value::Int(0) };
+        }
+
+        return Ok(lhs);
+    }
+
+    /// Applies the binary operator `op` to operands and the tokens they start at.
+    /// `eval_rhs` is false if `rhs` was skipped by `&&` or `||`.
+    fn apply(&self, op: &Token, (lhs, lhs_start): (Value, &Token), (rhs, rhs_start): (Value, &Token), eval_rhs: bool) -> Result<Value> {
+        if ["==", "!="].contains(&op.text.as_str()) && (matches!(lhs, Value::Str(_)) || matches!(rhs, Value::Str(_))) {
+            let equal = self.string(lhs, lhs_start)? == self.string(rhs, rhs_start)?;
+            return Ok(Value::Int((equal == (op.text == "==")) as i64));
+        }
+
+        let lhs = self.int(lhs, lhs_start)?;
+        let rhs = if eval_rhs { self.int(rhs, rhs_start)? } else { 0 };
+        let res = match op.text.as_str() {
+            "||" => Some((lhs != 0 || rhs != 0) as i64),
+            "&&" => Some((lhs != 0 && rhs != 0) as i64),
+            "|" => Some(lhs | rhs),
+            "^" => Some(lhs ^ rhs),
+            "&" => Some(lhs & rhs),
+            "==" => Some((lhs == rhs) as i64),
+            "!=" => Some((lhs != rhs) as i64),
+            "<" => Some((lhs < rhs) as i64),
+            "<=" => Some((lhs <= rhs) as i64),
+            ">" => Some((lhs > rhs) as i64),
+            ">=" => Some((lhs >= rhs) as i64),
+            "<<" | ">>" => {
+                let Ok(shift @ 0..64) = u32::try_from(rhs) else {
+                    return Err(self.error(op, &format!("shift by {} is out of range", rhs)));
+                };
+                Some(if op.text == "<<" { lhs << shift } else { lhs >> shift })
+            },
+            "+" => lhs.checked_add(rhs),
+            "-" => lhs.checked_sub(rhs),
+            "*" => lhs.checked_mul(rhs),
+            "/" | "%" if rhs == 0 => return Err(self.error(op, "division by zero")),
+            "/" => lhs.checked_div(rhs),
+            "%" => lhs.checked_rem(rhs),
+            _ => unreachable!("`{}` isn't a binary operator", op.text),
+        };
+
+        return res.map(Value::Int).ok_or_else(|| self.error(op, "integer overflow"));
+    }
+
+    fn unary(&mut self, eval: bool) -> Result<Value> {
+        let token = self.peek().clone();
+        if token.kind == Kind::Punct && ["!", "~", "-", "+"].contains(&token.text.as_str()) {
+            self.next();
+            let start = self.peek().clone();
+            let value = self.unary(eval)?;
+            if !eval {
+                return Ok(Value::Int(0));
+            }
+
+            let value = self.int(value, &start)?;
+            return match token.text.as_str() {
+                "!" => Ok(Value::Int((value == 0) as i64)),
+                "~" => Ok(Value::Int(!value)),
+                "-" => value.checked_neg().map(Value::Int).ok_or_else(|| self.error(&token, "integer overflow")),
+                _ => Ok(Value::Int(value)),
+            };
+        }
+
+        return self.primary(eval);
+    }
+
+    fn primary(&mut self, eval: bool) -> Result<Value> {
+        let token = self.next();
+        return match &token.kind {
+            Kind::Number(value) => Ok(Value::Int(*value)),
+            Kind::Str(value) => Ok(Value::Str(value.clone())),
+            Kind::Ident(_) if token.text == "true" => Ok(Value::Int(1)),
+            Kind::Ident(_) if token.text == "false" => Ok(Value::Int(0)),
+            Kind::Ident(_) if self.eat("(") => {
+                let mut args = vec![];
+                if !self.eat(")") {
+                    loop {
+                        let start = self.peek().clone();
+                        args.push((self.ternary(eval)?, start));
+                        if self.eat(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                if !eval {
+                    return Ok(Value::Int(0));
+                }
+                self.call(&token, args)
+            },
+            Kind::Ident(_) => Ok(Value::Word(token)),
+            Kind::Punct if token.text == "(" => {
+                let value = self.ternary(eval)?;
+                self.expect(")")?;
+                Ok(value)
+            },
+            _ => Err(self.unexpected(&token)),
+        };
+    }
+
+    /// Calls a builtin function:
+    ///
+    /// - `contains(s, sub)`, `starts_with(s, prefix)` and `ends_with(s, suffix)`
+    /// - `len(s)`, the number of characters in `s`
+    /// - `lower(s)` and `upper(s)`
+    fn call(&self, function: &Token, args: Vec<(Value, Token)>) -> Result<Value> {
+        let arity = match function.text.as_str() {
+            "contains" | "starts_with" | "ends_with" => 2,
+            "len" | "lower" | "upper" => 1,
+            name => return Err(self.error(function, &format!("unknown function `{}`", name))),
+        };
+        if args.len() != arity {
+            let message = format!("`{}` takes {} argument{}, found {}", function.text, arity, if arity == 1 { "" } else { "s" }, args.len());
+            return Err(self.error(function, &message));
+        }
+
+        let args = args.into_iter()
+            .map(|(value, start)| self.string(value, &start))
+            .collect::<Result<Vec<_>>>()?;
+
+        return Ok(match function.text.as_str() {
+            "contains" => Value::Int(args[0].contains(&args[1]) as i64),
+            "starts_with" => Value::Int(args[0].starts_with(&args[1]) as i64),
+            "ends_with" => Value::Int(args[0].ends_with(&args[1]) as i64),
+            "len" => Value::Int(args[0].chars().count() as i64),
+            "lower" => Value::Str(args[0].to_lowercase()),
+            _ => Value::Str(args[0].to_uppercase()),
+        });
+    }
+}
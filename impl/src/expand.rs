@@ -0,0 +1,528 @@
+//! Macro expansion.
+//!
+//! A line is split into tokens which are expanded from left to right. The result
+//! of every expansion is scanned again together with the rest of the line, so
+//! macros used in replacements and arguments are expanded regardless of the
+//! order in which they were defined. Every token remembers the macros it was
+//! produced by (its hide set), and a macro is never expanded again inside its
+//! own expansion.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::rc::Rc;
+
+use itertools::Itertools;
+
+use crate::{Error, ErrorKind, Result, State, is_defined};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// An identifier or a number
+    Word,
+    Whitespace,
+    /// Any other character
+    Punct,
+    /// A string literal created by the preprocessor or in a condition, which is never expanded
+    Literal,
+    /// The `##` operator in a function-like macro body
+    Paste,
+    /// An empty argument next to a `##` operator
+    Placemarker,
+}
+
+/// The names of the macros a token was produced by.
+type HideSet = Rc<[String]>;
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: Kind,
+    text: String,
+    hide: HideSet,
+    /// The bytes of the line the token was produced by
+    span: Range<usize>,
+}
+
+impl Token {
+    fn is_punct(&self, c: &str) -> bool {
+        self.kind == Kind::Punct && self.text == c
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Splits `input` into tokens. With `strings`, `"..."` is a single [Kind::Literal] token.
+fn tokenize(input: &str, hide: &HideSet, strings: bool) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if strings && c == '"' {
+            let mut escaped = false;
+            let mut end = input.len();
+            for (i, c) in chars.by_ref() {
+                if !escaped && c == '"' {
+                    end = i + 1;
+                    break;
+                }
+                escaped = !escaped && c == '\\';
+            }
+            tokens.push(Token { kind: Kind::Literal, text: input[start..end].to_string(), hide: hide.clone(), span: start..end });
+            continue;
+        }
+
+        let kind = if is_word_char(c) {
+            Kind::Word
+        } else if c.is_whitespace() {
+            Kind::Whitespace
+        } else {
+            Kind::Punct
+        };
+
+        let mut end = start + c.len_utf8();
+        if kind != Kind::Punct {
+            while let Some((i, c)) = chars.next_if(|(_, c)| if kind == Kind::Word { is_word_char(*c) } else { c.is_whitespace() }) {
+                end = i + c.len_utf8();
+            }
+        }
+
+        tokens.push(Token { kind, text: input[start..end].to_string(), hide: hide.clone(), span: start..end });
+    }
+
+    return tokens;
+}
+
+/// Marks `tokens` as produced by the bytes `span` of the line.
+fn with_span(tokens: Vec<Token>, span: &Range<usize>) -> Vec<Token> {
+    return tokens.into_iter().map(|token| Token { span: span.clone(), ..token }).collect();
+}
+
+fn union(a: &HideSet, b: &HideSet) -> HideSet {
+    if b.iter().all(|name| a.contains(name)) {
+        return a.clone();
+    }
+    return a.iter().chain(b.iter().filter(|name| !a.contains(name))).cloned().collect();
+}
+
+pub(crate) fn string_literal(value: &str) -> String {
+    return ["\"", &value.replace('\\', "\\\\").replace('"', "\\\""), "\""].concat();
+}
+
+fn next_non_whitespace(tokens: &[Token], start: usize) -> Option<usize> {
+    (start..tokens.len()).find(|i| tokens[*i].kind != Kind::Whitespace)
+}
+
+fn prev_non_whitespace(tokens: &[Token], end: usize) -> Option<usize> {
+    (0..end).rev().find(|i| tokens[*i].kind != Kind::Whitespace)
+}
+
+fn trim(tokens: &[Token]) -> &[Token] {
+    let start = next_non_whitespace(tokens, 0).unwrap_or(tokens.len());
+    let end = prev_non_whitespace(tokens, tokens.len()).map(|i| i + 1).unwrap_or(start);
+    return &tokens[start..end];
+}
+
+/// Splits `input`, which starts at the byte `offset` of the line, into tokens.
+fn tokenize_line(input: &str, offset: usize, strings: bool) -> Vec<Token> {
+    return tokenize(input, &HideSet::from([]), strings)
+        .into_iter()
+        .map(|token| Token { span: token.span.start + offset..token.span.end + offset, ..token })
+        .collect();
+}
+
+/// Expands all macros in `line`, which starts at the byte `offset` of the line being parsed.
+pub(crate) fn expand<'b, P>(line: &'b str, offset: usize, path: Option<&str>, line_num: usize, state: &mut State<'_, P>) -> Result<Cow<'b, str>> {
+    let mut expander = Expander { state, path, line_num, strings: false, expanded: false };
+    let tokens = expander.expand(tokenize_line(line, offset, false).into())?;
+
+    if !expander.expanded {
+        return Ok(Cow::Borrowed(line));
+    }
+
+    return Ok(Cow::Owned(tokens.iter().map(|token| token.text.as_str()).collect()));
+}
+
+/// Where a part of an expanded condition comes from.
+#[derive(Debug, Clone)]
+pub(crate) struct Origin {
+    /// The bytes of the expanded condition
+    pub(crate) range: Range<usize>,
+    /// The bytes of the line they were produced by
+    pub(crate) span: Range<usize>,
+    /// Whether they were produced by a macro
+    pub(crate) expanded: bool,
+}
+
+/// Replaces `defined` and `str` and expands all macros in the condition of an `#if`
+/// or `#elif`, which starts at the byte `offset` of the line. String literals are left as is.
+///
+/// Returns the expanded condition and where every part of it comes from.
+pub(crate) fn expand_condition<P>(condition: &str, offset: usize, path: Option<&str>, line_num: usize, state: &mut State<'_, P>) -> Result<(String, Vec<Origin>)> {
+    let tokens = replace_defined(tokenize_line(condition, offset, true), condition, state)?;
+    let mut expander = Expander { state, path, line_num, strings: true, expanded: false };
+    let tokens = expander.expand(tokens.into())?;
+
+    let mut text = String::new();
+    let mut origins = Vec::with_capacity(tokens.len() + 1);
+    for token in tokens {
+        origins.push(Origin { range: text.len()..text.len() + token.text.len(), span: token.span, expanded: !token.hide.is_empty() });
+        text += &token.text;
+    }
+    // The end of the condition
+    let end = offset + condition.len();
+    origins.push(Origin { range: text.len()..text.len(), span: end..end, expanded: false });
+
+    return Ok((text, origins));
+}
+
+/// Replaces every `defined(NAME)` and `defined NAME` with `true` or `false`, and every
+/// `str(NAME)` with the value of `NAME` as a string literal, before any other substitution takes place.
+fn replace_defined<P>(tokens: Vec<Token>, condition: &str, state: &State<'_, P>) -> Result<Vec<Token>> {
+    let mut out = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        let open = next_non_whitespace(&tokens, i + 1).filter(|open| tokens[*open].is_punct("("));
+
+        if token.kind == Kind::Word && token.text == "defined" {
+            let name = next_non_whitespace(&tokens, open.unwrap_or(i) + 1).filter(|name| tokens[*name].kind == Kind::Word);
+            let end = match (name, open) {
+                (Some(name), Some(_)) => next_non_whitespace(&tokens, name + 1).filter(|close| tokens[*close].is_punct(")")),
+                (name, None) => name,
+                (None, Some(_)) => None,
+            };
+            let (Some(name), Some(end)) = (name, end) else {
+                return Err(Error::from(ErrorKind::InvalidDefinedOperator).at(token.span.clone()));
+            };
+
+            let text = if is_defined(&tokens[name].text, &state.replacements, &state.fn_replacements) { "true" } else { "false" };
+            out.push(Token { kind: Kind::Literal, text: text.to_string(), hide: token.hide.clone(), span: token.span.start..tokens[end].span.end });
+            i = end + 1;
+            continue;
+        }
+
+        if token.kind == Kind::Word && token.text == "str" && let Some(open) = open {
+            let close = (open + 1..tokens.len()).find(|close| tokens[*close].is_punct(")"));
+            let name = trim(&tokens[open + 1..close.unwrap_or(tokens.len())]);
+            let value = match (name, close) {
+                ([name], Some(_)) if name.kind == Kind::Word => state.replacements.iter().find(|(n, _)| *n == name.text),
+                _ => None,
+            };
+            let span = token.span.start..close.map(|close| tokens[close].span.end).unwrap_or(token.span.end);
+            let Some((_, value)) = value else {
+                let name = name.iter().map(|token| token.text.as_str()).collect::<String>();
+                let message = format!("`str` takes the name of a substitution, found `{}`", name);
+                return Err(Error::from(ErrorKind::InvalidCondition(condition.trim().to_string(), message)).at(span));
+            };
+
+            out.push(Token { kind: Kind::Literal, text: string_literal(value.trim()), hide: token.hide.clone(), span });
+            i = close.unwrap() + 1;
+            continue;
+        }
+
+        out.push(token.clone());
+        i += 1;
+    }
+
+    return Ok(out);
+}
+
+struct Expander<'s, 'a, P> {
+    state: &'s mut State<'a, P>,
+    path: Option<&'s str>,
+    line_num: usize,
+    /// Whether string literals are tokens of their own
+    strings: bool,
+    /// Whether any macro was expanded
+    expanded: bool,
+}
+
+impl<P> Expander<'_, '_, P> {
+    fn expand(&mut self, mut input: VecDeque<Token>) -> Result<Vec<Token>> {
+        let mut out = Vec::with_capacity(input.len());
+
+        while let Some(token) = input.pop_front() {
+            if token.kind != Kind::Word || token.hide.contains(&token.text) {
+                out.push(token);
+                continue;
+            }
+
+            let hide = union(&token.hide, &HideSet::from([token.text.clone()]));
+
+            if let Some((_, replacement)) = self.state.replacements.iter().find(|(name, _)| *name == token.text) {
+                for token in with_span(tokenize(replacement, &hide, self.strings), &token.span).into_iter().rev() {
+                    input.push_front(token);
+                }
+                self.expanded = true;
+                continue;
+            }
+
+            if input.front().is_some_and(|token| token.is_punct("("))
+                && let Some((_, param_names, replacement)) = self.state.fn_replacements.iter().find(|(name, _, _)| *name == token.text)
+                && let Some(args) = take_args(&mut input)
+            {
+                let param_names = param_names.clone();
+                let replacement = replacement.clone();
+
+                // `##` right before or after a call in the text pastes the result to its neighbours
+                if out.len() >= 2 && out[out.len() - 2..].iter().all(|token| token.is_punct("#")) {
+                    out.truncate(out.len() - 2);
+                }
+                if input.len() >= 2 && input.range(..2).all(|token| token.is_punct("#")) {
+                    input.drain(..2);
+                }
+
+                let result = self.substitute(&token, &replacement, &param_names, args, &hide)?;
+                for token in result.into_iter().rev() {
+                    input.push_front(token);
+                }
+                self.expanded = true;
+                continue;
+            }
+
+            if let Some(text) = self.builtin(&token.text) {
+                out.push(Token { kind: Kind::Literal, text, hide, span: token.span });
+                self.expanded = true;
+                continue;
+            }
+
+            out.push(token);
+        }
+
+        return Ok(out);
+    }
+
+    fn builtin(&mut self, name: &str) -> Option<String> {
+        return match name {
+            "__FILE__" => Some(string_literal(self.path.unwrap_or("<input>"))),
+            "__LINE__" => Some((self.line_num + 1).to_string()),
+            "__INCLUDE_LEVEL__" => Some(self.state.include_level.to_string()),
+            "__COUNTER__" => {
+                self.state.counter += 1;
+                Some((self.state.counter - 1).to_string())
+            },
+            _ => None,
+        };
+    }
+
+    /// Substitutes the arguments of a function-like macro invocation into its body.
+    /// `name` is the token naming the macro.
+    fn substitute(
+        &mut self,
+        name: &Token,
+        replacement: &str,
+        param_names: &[String],
+        mut args: Vec<Vec<Token>>,
+        hide: &HideSet,
+    ) -> Result<Vec<Token>> {
+        // `NAME()` passes no arguments rather than one empty argument
+        if param_names.is_empty() && args.len() == 1 && trim(&args[0]).is_empty() {
+            args.clear();
+        }
+
+        let (param_names, va_args) = match param_names.split_last() {
+            Some((last, named)) if last == "..." => {
+                if args.len() < named.len() {
+                    return Err(Error::from(ErrorKind::NotEnoughParametersMacro(name.text.clone())).at(name.span.clone()));
+                }
+                let rest = args.split_off(named.len());
+                let comma = Token { kind: Kind::Punct, text: ",".to_string(), hide: HideSet::from([]), span: name.span.clone() };
+                let va_args = Itertools::intersperse(rest.into_iter(), vec![comma])
+                    .flatten()
+                    .collect::<Vec<_>>();
+                (named, Some(va_args))
+            },
+            _ => {
+                if args.len() < param_names.len() {
+                    return Err(Error::from(ErrorKind::NotEnoughParametersMacro(name.text.clone())).at(name.span.clone()));
+                } else if args.len() > param_names.len() {
+                    return Err(Error::from(ErrorKind::UnusedParametersMacro(name.text.clone())).at(name.span.clone()));
+                }
+                (param_names, None)
+            },
+        };
+
+        let has_va_args = va_args.as_ref().map(|va_args| !trim(va_args).is_empty());
+        let params = param_names.iter()
+            .map(|name| name.as_str())
+            .zip(args)
+            .chain(va_args.map(|va_args| ("__VA_ARGS__", va_args)))
+            .map(|(name, arg)| (name, trim(&arg).to_vec()))
+            .collect::<Vec<_>>();
+        let param_index = |token: &Token| (token.kind == Kind::Word)
+            .then(|| params.iter().position(|(name, _)| *name == token.text))
+            .flatten();
+
+        let mut body = with_span(tokenize(replacement, hide, self.strings), &name.span);
+        if let Some(has_va_args) = has_va_args {
+            body = replace_va_opt(body, has_va_args);
+        }
+
+        // Mark `##` operators, so that `#` characters in arguments are never pasted
+        let mut i = 0;
+        while i + 1 < body.len() {
+            if body[i].is_punct("#") && body[i + 1].is_punct("#") {
+                body.splice(i..i + 2, [Token { kind: Kind::Paste, text: "##".to_string(), hide: hide.clone(), span: name.span.clone() }]);
+            }
+            i += 1;
+        }
+
+        let mut expanded_args: Vec<Option<Vec<Token>>> = vec![None; params.len()];
+        let mut out = Vec::with_capacity(body.len());
+        let mut i = 0;
+        while i < body.len() {
+            let token = &body[i];
+
+            // `#param` is replaced by the argument as a string literal
+            if token.is_punct("#")
+                && let Some(next) = next_non_whitespace(&body, i + 1)
+                && let Some(param) = param_index(&body[next])
+            {
+                let text = params[param].1.iter().map(|token| token.text.as_str()).collect::<String>();
+                out.push(Token { kind: Kind::Literal, text: string_literal(&text), hide: hide.clone(), span: name.span.clone() });
+                i = next + 1;
+                continue;
+            }
+
+            if let Some(param) = param_index(token) {
+                let is_pasted = prev_non_whitespace(&body, i).is_some_and(|prev| body[prev].kind == Kind::Paste)
+                    || next_non_whitespace(&body, i + 1).is_some_and(|next| body[next].kind == Kind::Paste);
+
+                if is_pasted {
+                    // Operands of `##` are not expanded before pasting
+                    if params[param].1.is_empty() {
+                        out.push(Token { kind: Kind::Placemarker, text: String::new(), hide: hide.clone(), span: name.span.clone() });
+                    } else {
+                        out.extend(params[param].1.iter().cloned());
+                    }
+                } else {
+                    if expanded_args[param].is_none() {
+                        expanded_args[param] = Some(self.expand(params[param].1.iter().cloned().collect())?);
+                    }
+                    out.extend(expanded_args[param].iter().flatten().cloned());
+                }
+                i += 1;
+                continue;
+            }
+
+            out.push(token.clone());
+            i += 1;
+        }
+
+        return Ok(paste(out, self.strings)
+            .into_iter()
+            .map(|token| Token { hide: union(&token.hide, hide), ..token })
+            .collect());
+    }
+}
+
+/// Removes the arguments of a function-like macro invocation from `input`, if
+/// `input` starts with a complete argument list.
+fn take_args(input: &mut VecDeque<Token>) -> Option<Vec<Vec<Token>>> {
+    let mut depth = 0;
+    let mut separators = vec![0];
+    let mut close = None;
+    for (i, token) in input.iter().enumerate().skip(1) {
+        if token.is_punct("(") {
+            depth += 1;
+        } else if token.is_punct(")") {
+            if depth == 0 {
+                close = Some(i);
+                break;
+            }
+            depth -= 1;
+        } else if token.is_punct(",") && depth == 0 {
+            separators.push(i);
+        }
+    }
+
+    let close = close?;
+    separators.push(close);
+
+    let tokens = input.drain(..=close).collect::<Vec<_>>();
+    return Some(separators.windows(2)
+        .map(|range| tokens[range[0] + 1..range[1]].to_vec())
+        .collect());
+}
+
+/// Replaces every `__VA_OPT__(content)` with `content` if the variadic arguments
+/// are not empty, or removes it otherwise.
+fn replace_va_opt(body: Vec<Token>, has_va_args: bool) -> Vec<Token> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut i = 0;
+    while i < body.len() {
+        if body[i].kind == Kind::Word
+            && body[i].text == "__VA_OPT__"
+            && let Some(open) = next_non_whitespace(&body, i + 1)
+            && body[open].is_punct("(")
+        {
+            let mut depth = 0;
+            let close = (open + 1..body.len()).find(|j| {
+                if body[*j].is_punct("(") {
+                    depth += 1;
+                } else if body[*j].is_punct(")") {
+                    if depth == 0 {
+                        return true;
+                    }
+                    depth -= 1;
+                }
+                false
+            });
+
+            if let Some(close) = close {
+                if has_va_args {
+                    out.extend(body[open + 1..close].iter().cloned());
+                }
+                i = close + 1;
+                continue;
+            }
+        }
+
+        out.push(body[i].clone());
+        i += 1;
+    }
+
+    return out;
+}
+
+/// Applies the `##` operators in a substituted function-like macro body.
+fn paste(tokens: Vec<Token>, strings: bool) -> Vec<Token> {
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut pasting = false;
+
+    for token in tokens {
+        match token.kind {
+            Kind::Paste => {
+                while out.last().is_some_and(|token| token.kind == Kind::Whitespace) {
+                    out.pop();
+                }
+                pasting = true;
+            },
+            Kind::Whitespace if pasting => {},
+            _ if pasting => {
+                pasting = false;
+                match out.pop() {
+                    Some(lhs) if lhs.kind == Kind::Placemarker => out.push(token),
+                    Some(lhs) if token.kind == Kind::Placemarker => out.push(lhs),
+                    Some(lhs) => {
+                        let hide = union(&lhs.hide, &token.hide);
+                        let span = lhs.span.start.min(token.span.start)..lhs.span.end.max(token.span.end);
+                        let text = lhs.text + &token.text;
+                        if lhs.kind == Kind::Literal || token.kind == Kind::Literal {
+                            out.push(Token { kind: Kind::Literal, text, hide, span });
+                        } else {
+                            out.extend(with_span(tokenize(&text, &hide, strings), &span));
+                        }
+                    },
+                    None => out.push(token),
+                }
+            },
+            _ => out.push(token),
+        }
+    }
+
+    out.retain(|token| token.kind != Kind::Placemarker);
+    return out;
+}
@@ -1,72 +1,239 @@
+#![allow(clippy::needless_return)]
+
 use std::borrow::Cow;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
 use std::path::Path;
 // use std::path::{Path, PathBuf};
 
-use concat_string::concat_string;
-use eval::eval;
 use itertools::Itertools;
 use thiserror::Error;
 
-#[cfg(feature = "vfs")]
-use vfs::VfsPath;
+use expand::{expand, expand_condition};
+pub use loader::{FileSystem, SourceLoader, generate_include_table};
+pub use preprocessor::{Preprocessor, PreprocessorBuilder};
 
-#[cfg(not(feature = "vfs"))]
-type FeatPath = std::path::Path;
-#[cfg(feature = "vfs")]
-type FeatPath = vfs::VfsPath;
+mod condition;
+mod expand;
+mod loader;
+mod preprocessor;
 
-fn read_to_string(input_file: &FeatPath) -> Result<String> {
-    #[cfg(not(feature = "vfs"))] {
-        return read_to_string_std(input_file);
-    }
-    #[cfg(feature = "vfs")] {
-        let mut result = String::new();
-        input_file.open_file()?.read_to_string(&mut result)
-            .map_err(|err| Error::IOError(err, input_file.as_str().into()))?;
-        return Ok(result);
-    }
-}
+#[cfg(feature = "vfs")]
+use vfs::VfsPath;
 
 fn read_to_string_std(input_file: &Path) -> Result<String> {
     return std::fs::read_to_string(input_file)
-        .map_err(|err| Error::IOError(err, input_file.to_path_buf()));
+        .map_err(|err| ErrorKind::IOError(err, input_file.to_path_buf()).into());
 }
 
+/// What went wrong, see [Error] for where.
 #[derive(Error, Debug)]
-pub enum Error {
-    #[error("Invalid macro `{}` on line {}", .0, .1)]
-    InvalidMacro(String, usize),
-    #[error("Found extra parameters in #param macro on line {}", .0)]
-    ExtraParamsInMacro(usize, &'static str),
+pub enum ErrorKind {
+    #[error("Invalid macro `{}`", .0)]
+    InvalidMacro(String),
+    #[error("Found extra parameters in #{} macro", .0)]
+    ExtraParamsInMacro(&'static str),
     #[error("Not enough parameters passed to template file")]
     NotEnoughParameters,
     #[error("Unused parameters passed to template file")]
     UnusedParameters,
+    #[error("No value passed for parameter `{}`", .0)]
+    MissingParameter(String),
+    #[error("Unknown parameter `{}` passed to template file", .0)]
+    UnknownParameter(String),
+    #[error("Parameters of #include are either all passed by name or all by position")]
+    MixedParameters,
     #[error("Not enough parameters passed to function-like macro `{}`", .0)]
     NotEnoughParametersMacro(String),
     #[error("Too many parameters passed to function-like macro `{}`", .0)]
     UnusedParametersMacro(String),
-    #[error("Invalid parameter name {} on line {}", .0, .1)]
-    InvalidParameterName(String, usize),
-    #[error("First parameter of #include should be a string on line {}", .0)]
-    FirstParamOfIncludeNotString(usize),
+    #[error("Expected a macro name after #{}", .0)]
+    MissingMacroName(&'static str),
+    #[error("Invalid use of `defined` in condition")]
+    InvalidDefinedOperator,
+    #[error("Invalid parameter name {}", .0)]
+    InvalidParameterName(String),
+    #[error("First parameter of #include should be a string")]
+    FirstParamOfIncludeNotString,
+    #[error("Couldn't find include `{}`", .0)]
+    IncludeNotFound(String),
+    #[error("Include `{}` is outside of the base and include directories", .0)]
+    IncludeOutsideRoot(String),
+    #[error("Include cycle: {}", .0.join(" -> "))]
+    IncludeCycle(Vec<String>),
+    #[error("Includes nested deeper than {}: {}", .0, .1.join(" -> "))]
+    IncludeDepthExceeded(usize, Vec<String>),
     #[error("Invalid pragma '{}'", .0)]
     InvalidPragma(String),
     #[error("IOError while reading {}: {}", .1.display(), .0)]
     IOError(std::io::Error, std::path::PathBuf),
-    #[error("Couldn't evaluate `#if` expression condition: {}", .0)]
-    ConditionEvaluationError(#[from] eval::Error),
-    #[error("`#if` condition doesn't evaluate to bool '{}'", .0)]
-    NonBooleanConditionResult(eval::Value),
+    #[error("Invalid `#if` condition `{}`: {}", .0, .1)]
+    InvalidCondition(String, String),
     #[error("Elif specified after else")]
     ElifAfterElse,
+    #[error("Else specified after else")]
+    ElseAfterElse,
+    #[error("`#{}` without a matching `#if`", .0)]
+    ElseWithoutIf(&'static str),
+    #[error("`#endif` without a matching `#if`")]
+    EndifWithoutIf,
+    #[error("`#if` without a matching `#endif` in the same file")]
+    UnterminatedConditional,
+    #[error("{}", .0)]
+    UserError(String),
     #[cfg(feature = "vfs")]
     #[error("VfsError: {}", .0)]
     VfsError(#[from] vfs::VfsError),
 }
 
+/// A position in a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The file as written in the `#include`, `None` for the input itself
+    pub file: Option<String>,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, in characters
+    pub column: usize,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file.as_deref().unwrap_or("<input>"), self.line, self.column)
+    }
+}
+
+/// An error, with the location in the template it was found at.
+///
+/// `Display` renders the error like rustc does:
+///
+/// ```text
+/// Invalid macro `inclde`
+///  --> lib/lighting.wgsl:3:1
+///   |
+/// 3 | #inclde "brdf.wgsl"
+///   | ^^^^^^^^^^^^^^^^^^^
+///   = note: included from shader.wgsl:12:1
+/// ```
+#[derive(Debug)]
+pub struct Error(Box<ErrorData>);
+
+#[derive(Debug)]
+struct ErrorData {
+    kind: ErrorKind,
+    location: Option<SourceLocation>,
+    include_stack: Vec<SourceLocation>,
+    source_line: Option<String>,
+    /// The bytes of the source line the error points at, the whole line if `None`
+    span: Option<Range<usize>>,
+}
+
+impl Error {
+    /// What went wrong.
+    pub fn kind(&self) -> &ErrorKind {
+        return &self.0.kind;
+    }
+
+    /// Where the error was found, `None` if it isn't caused by a line of a template,
+    /// like a parameter that isn't used or a file that can't be read.
+    pub fn location(&self) -> Option<&SourceLocation> {
+        return self.0.location.as_ref();
+    }
+
+    /// The `#include`s that lead to [location](Self::location), innermost first.
+    pub fn include_stack(&self) -> &[SourceLocation] {
+        return &self.0.include_stack;
+    }
+
+    /// Points the error at the bytes `span` of the line it is found in, unless it already
+    /// points somewhere.
+    pub(crate) fn at(mut self, span: Range<usize>) -> Self {
+        if self.0.span.is_none() {
+            self.0.span = Some(span);
+        }
+        return self;
+    }
+}
+
+impl<T: Into<ErrorKind>> From<T> for Error {
+    fn from(kind: T) -> Self {
+        return Error(Box::new(ErrorData { kind: kind.into(), location: None, include_stack: vec![], source_line: None, span: None }));
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.kind)?;
+
+        let Some(location) = &self.0.location else {
+            return Ok(());
+        };
+        let line_num = location.line.to_string();
+        let pad = " ".repeat(line_num.len());
+        write!(f, "\n{pad}--> {location}")?;
+
+        if let Some(source_line) = &self.0.source_line {
+            let indent = source_line.chars().take(location.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+            let len = match self.0.span.as_ref().and_then(|span| source_line.get(span.clone())) {
+                Some(span) => span.chars().count(),
+                None => source_line.trim_end().chars().count().saturating_sub(location.column - 1),
+            }.max(1);
+            write!(f, "\n{pad} |\n{line_num} | {source_line}\n{pad} | {indent}{}", "^".repeat(len))?;
+        }
+
+        for include in &self.0.include_stack {
+            write!(f, "\n{pad} = note: included from {include}")?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return std::error::Error::source(&self.0.kind);
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
+/// The default of [PreprocessorBuilder::max_include_depth].
+///
+/// Every nested include takes some stack space, this keeps a debug build within a 2 MB thread stack.
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 100;
+
+/// A diagnostic emitted by a `#warning` directive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub message: String,
+    /// Where the `#warning` is
+    pub location: SourceLocation,
+    /// The `#include`s that lead to [location](Self::location), innermost first
+    pub include_stack: Vec<SourceLocation>,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at {})", self.message, self.location)
+    }
+}
+
+/// The result of processing a template.
+#[derive(Debug)]
+pub struct Output {
+    /// The expanded template
+    pub output: String,
+    /// Warnings emitted by `#warning` directives, in the order they were encountered
+    pub warnings: Vec<Warning>,
+    /// The errors that were recovered from, in the order they were encountered. Always
+    /// empty unless [PreprocessorBuilder::recover] is enabled.
+    pub errors: Vec<Error>,
+    /// The number of includes that weren't read again because of `#pragma once` or
+    /// an include guard
+    pub skipped_includes: usize,
+}
+
 /// Parses a file using the templating engine.
 ///
 /// For an example, see [parse_string].
@@ -75,7 +242,7 @@ type Result<T> = std::result::Result<T, Error>;
 /// - `input_file`: the file that is read
 /// - `base_dir`: all includes are resolved relative to this directory
 /// - `parameters`: if `input_file` contains any parameter macros, pass an iterator
-///                 to them here. Otherwise pass `std::iter::empty()`.
+///   to them here. Otherwise pass `std::iter::empty()`.
 pub fn parse<'a>(
     input_file: impl AsRef<Path>,
     base_dir: impl AsRef<Path>,
@@ -118,9 +285,12 @@ pub fn parse_cow<'a, Iter, C>(
         Iter: Iterator<Item = C>,
         C: Into<Cow<'a, str>>
 {
-    let content = read_to_string_std(input_file.as_ref())?;
-
-    return parse_string_cow(&content, base_dir, parameters);
+    return Preprocessor::builder()
+        .base_dir(base_dir.as_ref())
+        .parameters(parameters.map(|v| v.into().into_owned()))
+        .build()
+        .process_file(input_file.as_ref())
+        .map(|out| out.output);
 }
 
 #[cfg(feature = "vfs")]
@@ -133,9 +303,12 @@ pub fn parse_vfs_cow<'a, Iter, C>(
         Iter: Iterator<Item = C>,
         C: Into<Cow<'a, str>>
 {
-    let content = read_to_string(&input_file.into())?;
-
-    return parse_string_vfs_cow(&content, base_dir, parameters);
+    let base_dir: VfsPath = base_dir.into();
+    return Preprocessor::with_loader(base_dir)
+        .parameters(parameters.map(|v| v.into().into_owned()))
+        .build()
+        .process_file(input_file)
+        .map(|out| out.output);
 }
 
 /// Parses a file using the templating engine.
@@ -144,19 +317,17 @@ pub fn parse_vfs_cow<'a, Iter, C>(
 /// - `input`: the contents to process
 /// - `base_dir`: all includes are resolved relative to this directory
 /// - `parameters`: if `input` contains any parameter macros, pass an iterator
-///                 to them here. Otherwise pass `std::iter::empty()`.
+///   to them here. Otherwise pass `std::iter::empty()`.
 ///
 /// # Example
 ///
 /// ```rust
 /// # use ppx_impl::*;
-/// # #[cfg(not(feature = "vfs"))]
 /// let res = parse_string(
 ///     "#define A 4\nThe answer is A",
 ///     std::env::current_dir().unwrap(),
 ///     std::iter::empty()
 /// ).unwrap();
-/// # #[cfg(not(feature = "vfs"))]
 /// assert_eq!(res, "The answer is 4");
 /// ```
 pub fn parse_string<'a>(
@@ -187,11 +358,12 @@ pub fn parse_string_cow<'a, Iter, C>(
         Iter: Iterator<Item = C>,
         C: Into<Cow<'a, str>>
 {
-    #[cfg(not(feature = "vfs"))]
-    let base_dir = base_dir.as_ref();
-    #[cfg(feature = "vfs")]
-    let base_dir = VfsPath::from(vfs::PhysicalFS::new(base_dir));
-    parse_string_cow_impl(input, &base_dir, &mut parameters.map(|v| v.into()))
+    Preprocessor::builder()
+        .base_dir(base_dir.as_ref())
+        .parameters(parameters.map(|v| v.into().into_owned()))
+        .build()
+        .process_str(input)
+        .map(|out| out.output)
 }
 
 #[cfg(feature = "vfs")]
@@ -204,409 +376,744 @@ pub fn parse_string_vfs_cow<'a, Iter, C>(
         Iter: Iterator<Item = C>,
         C: Into<Cow<'a, str>>
 {
-    parse_string_cow_impl(input, &base_dir.into(), &mut parameters.map(|v| v.into()))
+    let base_dir: VfsPath = base_dir.into();
+    Preprocessor::with_loader(base_dir)
+        .parameters(parameters.map(|v| v.into().into_owned()))
+        .build()
+        .process_str(input)
+        .map(|out| out.output)
 }
 
-fn parse_string_cow_impl<'a>(
-    input: &str,
-    base_dir: &FeatPath,
-    parameters: &mut dyn Iterator<Item = Cow<'a, str>>
-) -> Result<String> {
-    let mut replacements: Vec<(String, Cow<str>)> = vec![];
-    let mut fn_replacements: Vec<(String, Vec<String>, String)> = vec![];
-    let mut visited_sources: Vec<String> = vec![];
+/// The options of a [Preprocessor].
+struct Options<'p, L: SourceLoader> {
+    loader: &'p L,
+    base_dir: &'p L::Path,
+    include_dirs: &'p [L::Path],
+    max_include_depth: usize,
+    /// Whether includes must stay inside `base_dir` and `include_dirs`
+    sandboxed: bool,
+    /// Whether to skip lines with an error and continue, instead of failing
+    recover: bool,
+    /// Macros defined before the input is parsed
+    replacements: &'p [(String, String)],
+    fn_replacements: &'p [(String, Vec<String>, String)],
+}
 
-    parse_string_cow_rec(input, None, base_dir, parameters, &mut replacements, &mut fn_replacements, &mut visited_sources)
-        .map(|str| str.unwrap())
+/// The values of the `#param`s of a file, passed by position or by name.
+struct Parameters<'a, 'i> {
+    positional: &'i mut dyn Iterator<Item = Cow<'a, str>>,
+    named: Vec<(String, Cow<'a, str>)>,
+    /// Whether any parameters were passed by name
+    is_named: bool,
 }
 
-fn parse_string_cow_rec<'a>(
-    input: &str,
-    path: Option<&str>,
-    base_dir: &FeatPath,
-    parameters: &mut dyn Iterator<Item = Cow<'a, str>>,
-    replacements: &mut Vec<(String, Cow<'a, str>)>,
-    fn_replacements: &mut Vec<(String, Vec<String>, String)>,
-    visited_sources: &mut Vec<String>,
-) -> Result<Option<String>> {
-    let mut out = String::new();
+impl<'a, 'i> Parameters<'a, 'i> {
+    fn new(positional: &'i mut dyn Iterator<Item = Cow<'a, str>>, named: Vec<(String, Cow<'a, str>)>) -> Self {
+        let is_named = !named.is_empty();
+        return Parameters { positional, named, is_named };
+    }
 
-    let mut cur_fn_replacement: Option<(String, Vec<String>, String)> = None;
-    let mut if_condition: Vec<(bool, bool, bool)> = vec![];
+    /// The value of `#param name`, or `default` if no value was passed for it.
+    fn take(&mut self, name: &str, default: Option<&str>) -> Result<Cow<'a, str>> {
+        if let Some(idx) = self.named.iter().position(|(n, _)| n == name) {
+            return Ok(self.named.remove(idx).1);
+        }
 
-    let max_lines = input.chars()
-        .filter(|c| *c == '\n')
-        .count();
+        return match (self.positional.next(), default) {
+            (Some(value), _) => Ok(value),
+            (None, Some(default)) => Ok(Cow::Owned(default.to_string())),
+            (None, None) if self.is_named => Err(ErrorKind::MissingParameter(name.to_string()).into()),
+            (None, None) => Err(ErrorKind::NotEnoughParameters.into()),
+        };
+    }
 
-    for (line_num, line) in input.lines().enumerate() {
-        let mut line_chars = line.chars().skip_while(char::is_ascii_whitespace);
-        let start_char = line_chars.by_ref().next();
-
-        let mut macro_name = None;
-
-        if let Some((include, _has_included, _is_end)) = if_condition.last() {
-            if !include {
-                if start_char == Some('#') {
-                    macro_name = Some(line_chars.by_ref().take_while(|c| c.is_ascii_alphanumeric()).collect::<String>());
-                    let macro_name = macro_name.as_ref().unwrap();
-                    if macro_name == "endif" {
-                        if_condition.pop();
-                        continue;
-                    } else if !(macro_name == "else" || macro_name == "elif") {
-                        continue;
-                    }
-                } else {
-                    continue;
-                }
-            }
+    /// Fails if any of the parameters weren't used by a `#param`.
+    fn finish(&mut self) -> Result<()> {
+        if self.positional.count() != 0 {
+            return Err(ErrorKind::UnusedParameters.into());
         }
 
-        if let Some(cur_fn_repl) = cur_fn_replacement {
-            if line.ends_with("\\") {
-                cur_fn_replacement = Some((cur_fn_repl.0, cur_fn_repl.1, cur_fn_repl.2 + &line[..line.len()-1]))
-            } else {
-                fn_replacements.push((cur_fn_repl.0, cur_fn_repl.1, cur_fn_repl.2 + line));
-                cur_fn_replacement = None;
-            }
-            continue;
+        if let Some((name, _)) = self.named.first() {
+            return Err(ErrorKind::UnknownParameter(name.clone()).into());
         }
 
-        match start_char {
-            Some('#') => {
-                if macro_name.is_none() {
-                    macro_name = Some(line_chars.by_ref().take_while(|c| c.is_ascii_alphanumeric()).collect::<String>());
-                }
-                match macro_name.as_ref().unwrap().as_str() {
-                    "define" => {
-                        let mut is_last_bracket = false;
-                        let name = line_chars.by_ref()
-                            .skip_while(char::is_ascii_whitespace)
-                            .take_while(|c| {
-                                if *c == '(' {
-                                    is_last_bracket = true;
-                                }
-                                !c.is_ascii_whitespace() && *c != '('
-                            })
-                            .collect::<String>();
-
-                        if is_last_bracket {
-                            let params = line_chars.by_ref()
-                                .take_while(|c| *c != ')')
-                                .chunk_by(|c| *c == ',');
-                            let params = params
-                                .into_iter()
-                                .filter(|(b, _)| !b)
-                                .map(|(_, i)| i
-                                    .skip_while(char::is_ascii_whitespace)
-                                    .take_while(|c| !c.is_ascii_whitespace())
-                                    .collect::<String>())
-                                .collect::<Vec<String>>();
-
-                            let check_param_name = params.iter().find(|param| !param.chars().all(|c| c.is_alphanumeric() || c == '_'))
-                                .or(params.iter().find(|param| param.len() == 0 || param.chars().next().unwrap().is_numeric()));
-                            if let Some(param_name) = check_param_name {
-                                return Err(Error::InvalidParameterName(param_name.clone(), line_num).into())
-                            }
-
-                            let replacement = line_chars.by_ref().collect::<String>();
+        return Ok(());
+    }
+}
 
-                            if replacement.ends_with("\\") {
-                                cur_fn_replacement = Some((name, params, replacement[..replacement.len()-1].to_string()));
-                            } else {
-                                fn_replacements.push((name, params, replacement));
-                            }
-                        } else {
-                            let replacement = line_chars.collect::<Cow<str>>();
-                            replacements.push((name, replacement))
-                        }
-                    }, "include" => {
-                        let path = line_chars.by_ref()
-                            .skip_while(char::is_ascii_whitespace)
-                            .take_while(|c| !c.is_ascii_whitespace())
-                            .collect::<String>();
-
-                        if !(path.starts_with('"') && path.ends_with('"')) {
-                            return Err(Error::FirstParamOfIncludeNotString(line_num).into());
-                        }
+/// A file in the chain of includes that is currently being parsed.
+struct IncludeFrame<P> {
+    /// The path as written in the `#include`
+    name: String,
+    /// The canonical path of the file
+    file: Option<P>,
+    /// The [State::macros_hash] when the file was entered
+    macros: u64,
+    /// The line that is being parsed, 0-based
+    line: usize,
+    /// The 1-based column of the first character of that line that isn't whitespace
+    column: usize,
+}
 
-                        let path = &path[1..path.len()-1];
+/// The state shared between a file and all files it includes.
+struct State<'a, P> {
+    replacements: Vec<(String, Cow<'a, str>)>,
+    fn_replacements: Vec<(String, Vec<String>, String)>,
+    /// The canonical paths of the files containing `#pragma once`
+    visited_sources: Vec<P>,
+    /// The canonical paths of the files wrapped in an include guard, and the guard macro
+    include_guards: Vec<(P, String)>,
+    skipped_includes: usize,
+    warnings: Vec<Warning>,
+    /// The errors that were recovered from
+    errors: Vec<Error>,
+    /// Next value of `__COUNTER__`
+    counter: usize,
+    /// Value of `__INCLUDE_LEVEL__`
+    include_level: usize,
+    include_stack: Vec<IncludeFrame<P>>,
+}
 
-                        let params = line_chars.by_ref()
-                            .chunk_by(|c| *c == ',');
-                        let mut params = params
-                            .into_iter()
-                            .filter(|(b, _)| !b)
-                            .map(|(_, i)| Cow::Owned(i.collect::<String>()));
-
-                        #[cfg(not(feature = "vfs"))]
-                        let file_path = base_dir.join(path);
-
-                        #[cfg(feature = "vfs")]
-                        let file_path = base_dir.join(path)?;
-
-                        let content = read_to_string(&file_path)?;
-
-                        match parse_string_cow_rec(&content, Some(path), &base_dir, &mut params, replacements, fn_replacements, visited_sources) {
-                            Ok(Some(res)) => {
-                                out += res.as_str();
-                                visited_sources.push(path.to_string());
-                            },
-                            Ok(None) => {},
-                            Err(err) => return Err(err),
-                        };
-                    }, "param" => {
-                        let param_name = line_chars.by_ref()
-                            .skip_while(|c| c.is_ascii_whitespace())
-                            .take_while(|c| !c.is_ascii_whitespace())
-                            .collect::<String>();
-
-                        if !line_chars.by_ref().all(|c| c.is_ascii_whitespace()) {
-                            return Err(Error::ExtraParamsInMacro(line_num, "param"));
-                        }
+impl<P> State<'_, P> {
+    /// A hash of the macros that are in effect, which are the first definitions of each name.
+    fn macros_hash(&self) -> u64 {
+        let mut seen = HashSet::with_capacity(self.replacements.len());
+        let mut replacements = self.replacements.iter()
+            .filter(|(name, _)| seen.insert(name))
+            .collect::<Vec<_>>();
+        replacements.sort_unstable();
+        let mut seen = HashSet::with_capacity(self.fn_replacements.len());
+        let mut fn_replacements = self.fn_replacements.iter()
+            .filter(|(name, _, _)| seen.insert(name))
+            .collect::<Vec<_>>();
+        fn_replacements.sort_unstable();
+
+        let mut hasher = std::hash::DefaultHasher::new();
+        (replacements, fn_replacements).hash(&mut hasher);
+        return hasher.finish();
+    }
 
-                        let Some(param_value) = parameters.next() else {
-                            return Err(Error::NotEnoughParameters);
-                        };
+    /// The names of the files in the include stack, followed by `name`.
+    fn include_chain(&self, name: &str) -> Vec<String> {
+        return self.include_stack.iter()
+            .map(|frame| frame.name.clone())
+            .chain([name.to_string()])
+            .collect();
+    }
 
-                        replacements.push((param_name, param_value));
-                    }, "pragma" => {
-                        let param_name = line_chars.by_ref()
-                            .skip_while(|c| c.is_ascii_whitespace())
-                            .take_while(|c| !c.is_ascii_whitespace())
-                            .collect::<String>();
+    /// Sets the line that is being parsed in the innermost file.
+    fn enter_line(&mut self, line_num: usize, line: &str) {
+        let frame = self.include_stack.last_mut().unwrap();
+        frame.line = line_num;
+        frame.column = line.chars().position(|c| !c.is_whitespace()).unwrap_or(0) + 1;
+    }
 
-                        if !line_chars.by_ref().all(|c| c.is_ascii_whitespace()) {
-                            return Err(Error::ExtraParamsInMacro(line_num, "pragma"));
-                        }
+    /// Records `err` if recovering from errors, returns it otherwise.
+    fn report(&mut self, err: Error, recover: bool) -> Result<()> {
+        if !recover {
+            return Err(err);
+        }
+        self.errors.push(err);
+        return Ok(());
+    }
 
-                        if param_name != "once" {
-                            return Err(Error::InvalidPragma(param_name));
-                        }
+    /// The location that is being parsed, followed by the `#include`s that lead to it.
+    fn locations(&self) -> impl Iterator<Item = SourceLocation> + '_ {
+        return self.include_stack.iter().rev().map(|frame| SourceLocation {
+            file: frame.file.is_some().then(|| frame.name.clone()),
+            line: frame.line + 1,
+            column: frame.column,
+        });
+    }
 
-                        if let Some(path) = path {
-                            if visited_sources.iter().find(|p| p.as_str() == path).is_some() {
-                                return Ok(None);
-                            }
-                        }
-                    }, "if" => {
-                        let condition = line_chars.collect::<String>();
-                        let condition = fn_replace(replace(&condition, &replacements), &fn_replacements)?;
-                        let res = eval(&condition)?;
-
-                        let Some(res) = res.is_boolean().then(|| res.as_bool().unwrap())
-                            .or_else(|| res.is_string().then(|| res.as_str().unwrap() == "true"))
-                            .or_else(|| res.is_i64().then(|| res.as_i64().unwrap() == 1))
-                            .or_else(|| res.is_u64().then(|| res.as_u64().unwrap() == 1))
-                        else {
-                            return Err(Error::NonBooleanConditionResult(res));
-                        };
-
-                        if_condition.push((res, res, false));
-                    }, "elif" => {
-                        if if_condition.last().map(|r| r.2).unwrap_or(false) {
-                            return Err(Error::ElifAfterElse);
-                        }
+    /// Attaches the location that is being parsed to `err`, unless it already has one.
+    /// `source_line` is the line at that location.
+    fn locate(&self, mut err: Error, source_line: &str) -> Error {
+        if err.0.location.is_some() {
+            return err;
+        }
 
-                        let condition = line_chars.collect::<String>();
-                        let condition = fn_replace(replace(&condition, &replacements), &fn_replacements)?;
-                        let res = eval(&condition)?;
-
-                        let Some(res) = res.as_bool() else {
-                            return Err(Error::NonBooleanConditionResult(res));
-                        };
-
-                        let last_idx = if_condition.len() - 1;
-                        if_condition[last_idx].0 = res;
-                        if_condition[last_idx].1 |= res;
-                    }, "else" => {
-                        let last_idx = if_condition.len() - 1;
-                        if_condition[last_idx].0 = !if_condition[last_idx].1;
-                        if_condition[last_idx].2 = true;
-                    }, "endif" => {
-                        if_condition.pop();
-                    },
-                    _ => return Err(Error::InvalidMacro(macro_name.unwrap(), line_num)),
-                }
-            },
-            Some('\\') if (line_chars.next() == Some('#')) => {
-                out += fn_replace(replace(&line.replacen("\\#", "#", 1), &replacements), &fn_replacements)?.as_ref();
-                if line_num != max_lines {
-                    out += "\n";
-                }
-            },
-            _ => {
-                out += fn_replace(replace(line, &replacements), &fn_replacements)?.as_ref();
-                if line_num != max_lines {
-                    out += "\n";
-                }
-            },
+        let mut locations = self.locations();
+        err.0.location = locations.next();
+        if let Some(location) = &mut err.0.location
+            && let Some(span) = err.0.span.as_ref().and_then(|span| source_line.get(..span.start))
+        {
+            location.column = span.chars().count() + 1;
         }
+        err.0.include_stack = locations.collect();
+        err.0.source_line = Some(source_line.to_string());
+        return err;
     }
+}
 
-    if parameters.count() != 0 {
-        return Err(Error::UnusedParameters);
+fn parse_string_cow_impl<'a, L: SourceLoader>(
+    input: &str,
+    file: Option<L::Path>,
+    options: &Options<'a, L>,
+    parameters: &mut Parameters<'a, '_>,
+) -> Result<Output> {
+    let path = file.as_ref().map(|file| options.loader.display(file));
+    let dir = file.as_ref().and_then(|file| options.loader.parent(file));
+    let mut state = State {
+        replacements: options.replacements.iter()
+            .map(|(name, value)| (name.clone(), Cow::Borrowed(value.as_str())))
+            .collect(),
+        fn_replacements: options.fn_replacements.to_vec(),
+        visited_sources: vec![],
+        include_guards: vec![],
+        skipped_includes: 0,
+        warnings: vec![],
+        errors: vec![],
+        counter: 0,
+        include_level: 0,
+        include_stack: vec![IncludeFrame {
+            name: path.clone().unwrap_or_else(|| "<input>".to_string()),
+            file: file.as_ref().map(|file| options.loader.canonicalize(file)),
+            macros: 0,
+            line: 0,
+            column: 1,
+        }],
+    };
+    state.include_stack[0].macros = state.macros_hash();
+
+    let output = parse_string_cow_rec(input, path.as_deref(), dir.as_ref(), options, parameters, &mut state)?;
+    if let Err(err) = parameters.finish() {
+        state.report(err, options.recover)?;
     }
 
-    return Ok(Some(out));
+    return Ok(Output { output, warnings: state.warnings, errors: state.errors, skipped_includes: state.skipped_includes });
 }
 
-/// Returns Some((start, end)) if start, end contains an identifier
-fn ident_range(str: &str, start: usize, end: usize) -> Option<(usize, usize)> {
-    if (start == 0 || str.chars().nth(start - 1).map(|c| !(c.is_alphanumeric() || c == '_')).unwrap_or(true))
-        && str.chars().nth(end).map(|c| !(c.is_alphanumeric() || c == '_')).unwrap_or(true)
-    {
-        return Some((start, end));
-    } else {
-        return None;
+/// The state of parsing a single file.
+struct FileState<'f, P> {
+    /// The path of the file as written in the `#include`, `None` for the input
+    path: Option<&'f str>,
+    /// The directory of the file, or `None` if the input isn't a file
+    dir: Option<&'f P>,
+    /// The index of the last line
+    max_lines: usize,
+    out: String,
+    /// A function-like macro whose body continues on the next line
+    cur_fn_replacement: Option<(String, Vec<String>, String)>,
+    /// The enclosing conditionals, innermost last
+    if_condition: Vec<Conditional>,
+}
+
+/// An `#if`, `#ifdef` or `#ifndef` that hasn't been closed yet.
+struct Conditional {
+    /// Whether the current branch is included
+    included: bool,
+    /// Whether any branch was included
+    any_included: bool,
+    /// Whether the `#else` was reached
+    seen_else: bool,
+    /// The line of the `#if`
+    line: usize,
+}
+
+impl Conditional {
+    /// A conditional whose first branch is included if `included` is set.
+    fn new(included: bool, line: usize) -> Self {
+        return Conditional { included, any_included: included, seen_else: false, line };
+    }
+
+    /// A conditional none of whose branches are included.
+    fn skipped(line: usize) -> Self {
+        return Conditional { included: false, any_included: true, seen_else: false, line };
     }
 }
 
-fn ident_or_paste_range(str: &str, start: usize, end: usize) -> Option<(usize, usize)> {
-    if start >= 2 && str.chars().skip(start - 2).take(2).all(|c| c == '#') {
-        let mut iter = str.chars().skip(end).take(2).tee();
-        // ##IDENT##
-        if iter.0.count() == 2 && iter.1.all(|c| c == '#') {
-            Some((start - 2, end + 2))
-        // ##IDENT
-        } else if str.chars().nth(end).map(|c| !(c.is_alphanumeric() || c == '_')).unwrap_or(true) {
-            Some((start - 2, end))
+/// `dir` is the directory of the file being parsed, or `None` if the input isn't a file.
+fn parse_string_cow_rec<'a, L: SourceLoader>(
+    input: &str,
+    path: Option<&str>,
+    dir: Option<&L::Path>,
+    options: &Options<L>,
+    parameters: &mut Parameters<'a, '_>,
+    state: &mut State<'a, L::Path>,
+) -> Result<String> {
+    let mut file_state = FileState {
+        path,
+        dir,
+        max_lines: input.chars()
+            .filter(|c| *c == '\n')
+            .count(),
+        out: String::new(),
+        cur_fn_replacement: None,
+        if_condition: vec![],
+    };
+
+    for (line_num, line) in input.lines().enumerate() {
+        state.enter_line(line_num, line);
+
+        if let Err(err) = parse_line(line, line_num, options, parameters, state, &mut file_state) {
+            // The line is skipped when recovering
+            let err = state.locate(err, line);
+            state.report(err, options.recover)?;
+        }
+    }
+
+    // Conditionals can't continue in, or be closed by, the file including this one
+    for if_line in file_state.if_condition.iter().map(|cond| cond.line) {
+        let line = input.lines().nth(if_line).unwrap_or_default();
+        state.enter_line(if_line, line);
+        let err = state.locate(ErrorKind::UnterminatedConditional.into(), line);
+        state.report(err, options.recover)?;
+    }
+
+    return Ok(file_state.out);
+}
+
+fn parse_line<'a, L: SourceLoader>(
+    line: &str,
+    line_num: usize,
+    options: &Options<L>,
+    parameters: &mut Parameters<'a, '_>,
+    state: &mut State<'a, L::Path>,
+    file_state: &mut FileState<L::Path>,
+) -> Result<()> {
+    let path = file_state.path;
+    let dir = file_state.dir;
+    let max_lines = file_state.max_lines;
+
+    let mut line_chars = line.chars().skip_while(char::is_ascii_whitespace);
+    let start_char = line_chars.by_ref().next();
+
+    let mut macro_name = None;
+
+    if let Some(cond) = file_state.if_condition.last()
+        && !cond.included
+    {
+        if start_char == Some('#') {
+            macro_name = Some(line_chars.by_ref().take_while(|c| c.is_ascii_alphanumeric()).collect::<String>());
+            let macro_name = macro_name.as_ref().unwrap();
+            if macro_name == "endif" {
+                file_state.if_condition.pop();
+                return Ok(());
+            } else if macro_name == "if" || macro_name == "ifdef" || macro_name == "ifndef" {
+                // Nested conditional inside a skipped branch; none of its branches can be included
+                file_state.if_condition.push(Conditional::skipped(line_num));
+                return Ok(());
+            } else if !(macro_name == "else" || macro_name == "elif") {
+                return Ok(());
+            }
         } else {
-            None
+            return Ok(());
         }
-    } else if start == 0 || str.chars().nth(start - 1).map(|c| !(c.is_alphanumeric() || c == '_')).unwrap_or(true) {
-        let mut iter = str.chars().skip(end).take(2).tee();
-        // IDENT##
-        if iter.0.count() == 2 && iter.1.all(|c| c == '#') {
-            Some((start, end + 2))
-        // IDENT
-        } else if str.chars().nth(end).map(|c| !(c.is_alphanumeric() || c == '_')).unwrap_or(true)  {
-            Some((start, end))
+    }
+
+    if let Some(cur_fn_repl) = file_state.cur_fn_replacement.take() {
+        if let Some(line) = line.strip_suffix("\\") {
+            file_state.cur_fn_replacement = Some((cur_fn_repl.0, cur_fn_repl.1, cur_fn_repl.2 + line))
         } else {
-            None
+            state.fn_replacements.push((cur_fn_repl.0, cur_fn_repl.1, cur_fn_repl.2 + line));
+            file_state.cur_fn_replacement = None;
         }
-    } else {
-        None
+        return Ok(());
     }
-}
 
-fn replace<'a>(line: &'a str, replacements: &Vec<(String, Cow<str>)>) -> Cow<'a, str> {
-    let mut out: Cow<str> = line.into();
+    match start_char {
+        Some('#') => {
+            if macro_name.is_none() {
+                macro_name = Some(line_chars.by_ref().take_while(|c| c.is_ascii_alphanumeric()).collect::<String>());
+            }
+            match macro_name.as_ref().unwrap().as_str() {
+                "define" => {
+                    let mut is_last_bracket = false;
+                    let name = line_chars.by_ref()
+                        .skip_while(char::is_ascii_whitespace)
+                        .take_while(|c| {
+                            if *c == '(' {
+                                is_last_bracket = true;
+                            }
+                            !c.is_ascii_whitespace() && *c != '('
+                        })
+                        .collect::<String>();
 
-    for replacement in replacements {
-        out = replace_all(out, &replacement.0, replacement.1.as_ref(), ident_range);
-    }
+                    if is_last_bracket {
+                        let params = line_chars.by_ref()
+                            .take_while(|c| *c != ')')
+                            .chunk_by(|c| *c == ',');
+                        let params = params
+                            .into_iter()
+                            .filter(|(b, _)| !b)
+                            .map(|(_, i)| i
+                                .skip_while(char::is_ascii_whitespace)
+                                .take_while(|c| !c.is_ascii_whitespace())
+                                .collect::<String>())
+                            .collect::<Vec<String>>();
 
-    return out;
-}
+                        check_macro_params(&params)?;
 
-fn fn_replace<'a>(line: Cow<'a, str>, replacements: &Vec<(String, Vec<String>, String)>) -> Result<Cow<'a, str>> {
-    let mut out: Cow<str> = line;
+                        let replacement = line_chars.by_ref().collect::<String>();
 
-    for replacement in replacements {
-        out = replace_all_fn(out, replacement.0.as_str(), replacement.2.as_str(), &replacement.1, ident_or_paste_range)?;
-    }
+                        if replacement.ends_with("\\") {
+                            file_state.cur_fn_replacement = Some((name, params, replacement[..replacement.len()-1].to_string()));
+                        } else {
+                            state.fn_replacements.push((name, params, replacement));
+                        }
+                    } else {
+                        let replacement = line_chars.collect::<Cow<str>>();
+                        state.replacements.push((name, replacement));
+                    }
+                }, "undef" => {
+                    let name = line_chars.by_ref()
+                        .skip_while(char::is_ascii_whitespace)
+                        .take_while(|c| !c.is_ascii_whitespace())
+                        .collect::<String>();
+
+                    if !line_chars.by_ref().all(|c| c.is_ascii_whitespace()) {
+                        return Err(ErrorKind::ExtraParamsInMacro("undef").into());
+                    }
 
-    return Ok(out);
-}
+                    if name.is_empty() {
+                        return Err(ErrorKind::MissingMacroName("undef").into());
+                    }
 
-fn replace_all<'a>(str: Cow<'a, str>, to_match: &str, replacement: &str, predicate: impl Fn(&str, usize, usize) -> Option<(usize, usize)>) -> Cow<'a, str> {
-    let matches = str.match_indices(to_match).collect::<Vec<_>>();
+                    state.replacements.retain(|(n, _)| *n != name);
+                    state.fn_replacements.retain(|(n, _, _)| *n != name);
+                }, "include" => {
+                    let path = line_chars.by_ref()
+                        .skip_while(char::is_ascii_whitespace)
+                        .take_while(|c| !c.is_ascii_whitespace())
+                        .collect::<String>();
+
+                    let is_system = path.starts_with('<') && path.ends_with('>');
+                    if !(path.starts_with('"') && path.ends_with('"') || is_system) || path.len() < 2 {
+                        return Err(ErrorKind::FirstParamOfIncludeNotString.into());
+                    }
 
-    let mut out: Option<Cow<str>> = None;
-    let mut end_idx = str.len();
+                    let path = &path[1..path.len()-1];
+
+                    let params = line_chars.by_ref()
+                        .chunk_by(|c| *c == ',');
+                    let params = params
+                        .into_iter()
+                        .filter(|(b, _)| !b)
+                        .map(|(_, i)| i.collect::<String>())
+                        .collect::<Vec<_>>();
+
+                    // Parameters are passed by name if all of them are `NAME=value`
+                    let (named, positional): (Vec<_>, Vec<_>) = params.into_iter()
+                        .map(|param| named_parameter(&param).ok_or(param))
+                        .partition(std::result::Result::is_ok);
+                    if !named.is_empty() && !positional.is_empty() {
+                        return Err(ErrorKind::MixedParameters.into());
+                    }
+                    let named = named.into_iter().map(std::result::Result::unwrap).collect::<Vec<_>>();
+                    let positional = positional.into_iter().map(std::result::Result::unwrap_err).collect::<Vec<_>>();
+                    let named = named.into_iter().map(|(name, value)| (name, Cow::Owned(value))).collect();
+                    let mut positional = positional.into_iter().map(Cow::Owned);
+                    let mut params = Parameters::new(&mut positional, named);
+
+                    let file_path = resolve_include(path, is_system, dir, options)?;
+                    let canonical_path = options.loader.canonicalize(&file_path);
+
+                    if state.visited_sources.contains(&canonical_path)
+                        || state.include_guards.iter()
+                            .any(|(file, guard)| *file == canonical_path && is_defined(guard, &state.replacements, &state.fn_replacements))
+                    {
+                        state.skipped_includes += 1;
+                        return Ok(());
+                    }
 
-    for (idx, _) in matches.into_iter().rev() {
-        if let Some((start, end)) = predicate(str.as_ref(), idx, idx + to_match.len()) {
-            let following_str = &str[end..end_idx];
-            end_idx = start;
-            out = out.map(|m| concat_string!(replacement, following_str, m.as_ref()).into())
-                .or(Some(concat_string!(replacement, following_str).into()));
-        }
-    }
+                    let content = options.loader.read(&file_path)?;
+
+                    // Entering a file again with the same macros would repeat the same includes forever,
+                    // unless the file stops the recursion with a builtin that changes on every include
+                    let macros = state.macros_hash();
+                    let is_bounded = content.contains("__INCLUDE_LEVEL__") || content.contains("__COUNTER__");
+                    if !is_bounded
+                        && let Some(start) = state.include_stack.iter()
+                            .position(|frame| frame.file.as_ref() == Some(&canonical_path) && frame.macros == macros)
+                    {
+                        return Err(ErrorKind::IncludeCycle(state.include_chain(path).split_off(start)).into());
+                    }
 
-    if end_idx != 0 {
-        out = out.map(|m| concat_string!(&str[0..end_idx], m.as_ref()).into())
-    }
+                    if state.include_level >= options.max_include_depth {
+                        return Err(ErrorKind::IncludeDepthExceeded(options.max_include_depth, state.include_chain(path)).into());
+                    }
 
-    return out.unwrap_or(str);
-}
+                    if let Some(guard) = include_guard(&content)
+                        && !state.include_guards.iter().any(|(file, _)| *file == canonical_path)
+                    {
+                        state.include_guards.push((canonical_path.clone(), guard));
+                    }
 
-fn replace_all_fn<'a>(
-    str: Cow<'a, str>,
-    name: &str,
-    replacement: &str,
-    param_names: &Vec<String>,
-    predicate: impl Fn(&str, usize, usize) -> Option<(usize, usize)>,
-) -> Result<Cow<'a, str>> {
-    let matches = str.match_indices(name).collect::<Vec<_>>();
+                    state.include_level += 1;
+                    state.include_stack.push(IncludeFrame { name: path.to_string(), file: Some(canonical_path), macros, line: 0, column: 1 });
+                    let res = parse_string_cow_rec(&content, Some(path), options.loader.parent(&file_path).as_ref(), options, &mut params, state);
+                    state.include_stack.pop();
+                    state.include_level -= 1;
+
+                    file_state.out += res?.as_str();
+                    params.finish()?;
+                }, "param" => {
+                    let param = line_chars.by_ref().collect::<String>();
+
+                    // `#param NAME = default`
+                    let (param_name, default) = match param.split_once('=') {
+                        Some((name, default)) => (name.trim(), Some(default.trim())),
+                        None => (param.trim(), None),
+                    };
+
+                    if param_name.contains(|c: char| c.is_ascii_whitespace()) {
+                        return Err(ErrorKind::ExtraParamsInMacro("param").into());
+                    }
 
-    let mut out: Option<Cow<str>> = None;
-    let mut end_idx = str.len();
+                    let param_name = param_name.to_string();
+                    let param_value = parameters.take(&param_name, default)?;
 
-    for (idx, _) in matches.into_iter().rev() {
-        let mut iter = str.chars();
-        if iter.by_ref().nth(idx + name.len()) != Some('(') {
-            continue;
-        }
+                    state.replacements.push((param_name, param_value));
+                }, "pragma" => {
+                    let param_name = line_chars.by_ref()
+                        .skip_while(|c| c.is_ascii_whitespace())
+                        .take_while(|c| !c.is_ascii_whitespace())
+                        .collect::<String>();
 
-        let mut parens = 0;
-        let mut params = Vec::new();
-        let mut cur = String::new();
-        let mut param_len = 0;
-        for (i, c) in iter.enumerate() {
-            if c == ')' {
-                parens -= 1;
-                if parens == -1 {
-                    params.push(cur);
-                    cur = String::new();
-                    param_len = i;
-                    break;
-                }
-            }
+                    if !line_chars.by_ref().all(|c| c.is_ascii_whitespace()) {
+                        return Err(ErrorKind::ExtraParamsInMacro("pragma").into());
+                    }
+
+                    if param_name != "once" {
+                        return Err(ErrorKind::InvalidPragma(param_name).into());
+                    }
+
+                    // Later includes of this file are skipped
+                    if let Some(file) = state.include_stack.last().and_then(|frame| frame.file.clone())
+                        && !state.visited_sources.contains(&file)
+                    {
+                        state.visited_sources.push(file);
+                    }
+                }, "error" => {
+                    return Err(ErrorKind::UserError(line_chars.collect::<String>().trim().to_string()).into());
+                }, "warning" => {
+                    let mut locations = state.locations();
+                    let warning = Warning {
+                        message: line_chars.collect::<String>().trim().to_string(),
+                        location: locations.next().unwrap(),
+                        include_stack: locations.collect(),
+                    };
+                    state.warnings.push(warning);
+                }, "ifdef" | "ifndef" => {
+                    let directive = if macro_name.as_ref().unwrap() == "ifdef" { "ifdef" } else { "ifndef" };
+                    // Pushed before the condition is checked, so that the `#endif` of an invalid
+                    // condition still matches when recovering from errors
+                    file_state.if_condition.push(Conditional::skipped(line_num));
+
+                    let name = line_chars.by_ref()
+                        .skip_while(char::is_ascii_whitespace)
+                        .take_while(|c| !c.is_ascii_whitespace())
+                        .collect::<String>();
+
+                    if name.is_empty() {
+                        return Err(ErrorKind::MissingMacroName(directive).into());
+                    }
 
-            // next param
-            if c == ',' && parens == 0 {
-                params.push(cur);
-                cur = String::new();
-                continue;
+                    if !line_chars.by_ref().all(|c| c.is_ascii_whitespace()) {
+                        return Err(ErrorKind::ExtraParamsInMacro(directive).into());
+                    }
+
+                    let res = is_defined(&name, &state.replacements, &state.fn_replacements) == (directive == "ifdef");
+
+                    *file_state.if_condition.last_mut().unwrap() = Conditional::new(res, line_num);
+                }, "if" => {
+                    file_state.if_condition.push(Conditional::skipped(line_num));
+
+                    let condition = line_chars.collect::<String>();
+                    let (condition, origins) = expand_condition(&condition, line.len() - condition.len(), path, line_num, state)?;
+                    let res = condition::evaluate(&condition, &origins)?;
+
+                    *file_state.if_condition.last_mut().unwrap() = Conditional::new(res, line_num);
+                }, "elif" => {
+                    let Some(cond) = file_state.if_condition.last_mut() else {
+                        return Err(ErrorKind::ElseWithoutIf("elif").into());
+                    };
+                    if cond.seen_else {
+                        return Err(ErrorKind::ElifAfterElse.into());
+                    }
+
+                    if cond.any_included {
+                        // An earlier branch was already included, so the condition isn't evaluated
+                        cond.included = false;
+                        return Ok(());
+                    }
+
+                    let condition = line_chars.collect::<String>();
+                    let (condition, origins) = expand_condition(&condition, line.len() - condition.len(), path, line_num, state)?;
+                    let res = condition::evaluate(&condition, &origins)?;
+
+                    let cond = file_state.if_condition.last_mut().unwrap();
+                    cond.included = res;
+                    cond.any_included |= res;
+                }, "else" => {
+                    let Some(cond) = file_state.if_condition.last_mut() else {
+                        return Err(ErrorKind::ElseWithoutIf("else").into());
+                    };
+                    if cond.seen_else {
+                        return Err(ErrorKind::ElseAfterElse.into());
+                    }
+                    cond.included = !cond.any_included;
+                    cond.seen_else = true;
+                }, "endif" => {
+                    if file_state.if_condition.pop().is_none() {
+                        return Err(ErrorKind::EndifWithoutIf.into());
+                    }
+                },
+                _ => return Err(ErrorKind::InvalidMacro(macro_name.unwrap()).into()),
+            }
+        },
+        Some('\\') if (line_chars.next() == Some('#')) => {
+            let escape = line.find('\\').unwrap();
+            file_state.out += &line[..escape];
+            file_state.out += expand(&line[escape + 1..], escape + 1, path, line_num, state)?.as_ref();
+            if line_num != max_lines {
+                file_state.out += "\n";
             }
+        },
+        _ => {
+            file_state.out += expand(line, 0, path, line_num, state)?.as_ref();
+            if line_num != max_lines {
+                file_state.out += "\n";
+            }
+        },
+    }
+
+    return Ok(());
+}
+
+/// Splits a `NAME=value` parameter of an `#include`.
+///
+/// `a==b` is a positional parameter.
+fn named_parameter(param: &str) -> Option<(String, String)> {
+    let (name, value) = param.split_once('=')?;
+    let name = name.trim();
+    if value.starts_with('=') || name.is_empty() || name.starts_with(|c: char| c.is_numeric()) || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    return Some((name.to_string(), value.trim().to_string()));
+}
 
-            if c == '(' {
-                parens += 1;
+/// Finds the file an `#include` refers to.
+///
+/// `"file"` is looked up in the directory of the including file `dir` and in
+/// `base_dir` before the include directories, `<file>` only in the include directories.
+fn resolve_include<L: SourceLoader>(
+    path: &str,
+    is_system: bool,
+    dir: Option<&L::Path>,
+    options: &Options<L>,
+) -> Result<L::Path> {
+    if options.sandboxed && (path.starts_with(['/', '\\']) || Path::new(path).is_absolute()) {
+        return Err(ErrorKind::IncludeOutsideRoot(path.to_string()).into());
+    }
+
+    let local_dirs = if is_system { vec![] } else { dir.into_iter().chain([options.base_dir]).collect() };
+    for dir in local_dirs.into_iter().chain(options.include_dirs) {
+        if let Some(file_path) = options.loader.resolve(dir, path)? {
+            if options.sandboxed && !is_inside_roots(&file_path, options) {
+                return Err(ErrorKind::IncludeOutsideRoot(path.to_string()).into());
             }
+            return Ok(file_path);
+        }
+    }
+
+    return Err(ErrorKind::IncludeNotFound(path.to_string()).into());
+}
 
-            cur.push(c);
+/// Whether the canonical path of `file` is inside `base_dir` or one of the include directories.
+fn is_inside_roots<L: SourceLoader>(file: &L::Path, options: &Options<L>) -> bool {
+    let roots = [options.base_dir].into_iter()
+        .chain(options.include_dirs)
+        .map(|dir| options.loader.canonicalize(dir))
+        .collect::<Vec<_>>();
+
+    let mut dir = options.loader.parent(&options.loader.canonicalize(file));
+    while let Some(cur) = dir {
+        if roots.contains(&cur) {
+            return true;
         }
+        dir = options.loader.parent(&cur);
+    }
+    return false;
+}
 
-        let to_replace_len = name.len() + 2 + param_len;
+/// Returns the guard macro if `content` is wrapped in a classic include guard: it
+/// starts with `#ifndef NAME` and `#define NAME`, and ends with the matching `#endif`.
+fn include_guard(content: &str) -> Option<String> {
+    fn directive(line: &str) -> Option<(&str, &str)> {
+        let line = line.trim_start().strip_prefix('#')?;
+        let name_len = line.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(line.len());
+        return Some((&line[..name_len], line[name_len..].trim()));
+    }
 
-        let Some((start, end)) = predicate(str.as_ref(), idx, idx + to_replace_len) else {
-            continue;
-        };
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    let guard = match directive(lines.next()?)? {
+        ("ifndef", name) if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') => name,
+        _ => return None,
+    };
+    match directive(lines.next()?)? {
+        ("define", definition) if definition.split_whitespace().next() == Some(guard) => {},
+        _ => return None,
+    }
 
-        if params.len() < param_names.len() {
-            return Err(Error::NotEnoughParametersMacro(name.to_string()));
-        } else if params.len() > param_names.len() {
-            return Err(Error::UnusedParametersMacro(name.to_string()))
+    let mut depth = 1;
+    for line in lines {
+        if depth == 0 {
+            // Part of the file is outside of the guard
+            return None;
+        }
+        match directive(line) {
+            Some(("if" | "ifdef" | "ifndef", _)) => depth += 1,
+            Some(("else" | "elif", _)) if depth == 1 => return None,
+            Some(("endif", _)) => depth -= 1,
+            _ => {},
         }
+    }
 
-        let params = param_names.iter()
-            .zip(params.iter().map(|p| p.trim()));
+    return (depth == 0).then(|| guard.to_string());
+}
 
-        let mut replacement = Cow::Borrowed(replacement);
-        for param in params {
-            replacement = replace_all(replacement, param.0, param.1, ident_or_paste_range);
-        }
+/// Whether `name` can be the name of a macro or parameter.
+fn is_identifier(name: &str) -> bool {
+    return name.chars().next().is_some_and(|c| !c.is_numeric())
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+}
 
-        let following_str = &str[end..end_idx];
-        end_idx = start;
-        out = out.map(|m| concat_string!(replacement, following_str, m.as_ref()).into())
-            .or(Some(concat_string!(replacement, following_str).into()));
+/// Checks the parameters of a function-like macro, of which only the last can be `...`.
+fn check_macro_params(params: &[String]) -> Result<()> {
+    // A trailing `...` makes the macro variadic
+    let named_params = match params.split_last() {
+        Some((last, named)) if last == "..." => named,
+        _ => params,
+    };
+    if let Some(param_name) = named_params.iter().find(|param| !is_identifier(param)) {
+        return Err(ErrorKind::InvalidParameterName(param_name.clone()).into());
     }
+    return Ok(());
+}
 
-    if end_idx != 0 {
-        out = out.map(|m| concat_string!(&str[0..end_idx], m.as_ref()).into());
+/// Checks the macros defined with [PreprocessorBuilder::define] and [PreprocessorBuilder::define_fn]
+/// like the `#define` directive does.
+fn check_predefined(replacements: &[(String, String)], fn_replacements: &[(String, Vec<String>, String)]) -> Result<()> {
+    for name in replacements.iter().map(|(name, _)| name).chain(fn_replacements.iter().map(|(name, _, _)| name)) {
+        if name.is_empty() {
+            return Err(ErrorKind::MissingMacroName("define").into());
+        } else if !is_identifier(name) {
+            return Err(ErrorKind::InvalidMacro(name.clone()).into());
+        }
     }
+    for (_, params, _) in fn_replacements {
+        check_macro_params(params)?;
+    }
+    return Ok(());
+}
+
+/// Macros whose value is computed by the preprocessor.
+const BUILTIN_MACROS: [&str; 4] = ["__FILE__", "__LINE__", "__COUNTER__", "__INCLUDE_LEVEL__"];
 
-    return Ok(out.unwrap_or(str));
+fn is_defined(name: &str, replacements: &[(String, Cow<str>)], fn_replacements: &[(String, Vec<String>, String)]) -> bool {
+    replacements.iter().any(|(n, _)| n == name)
+        || fn_replacements.iter().any(|(n, _, _)| n == name)
+        || BUILTIN_MACROS.contains(&name)
 }
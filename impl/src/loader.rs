@@ -0,0 +1,303 @@
+//! Where templates and the files they include are read from.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "vfs")]
+use vfs::VfsPath;
+
+use crate::{ErrorKind, Result, read_to_string_std};
+
+/// Finds and reads the files a template includes.
+///
+/// Implemented for [FileSystem], `vfs::VfsPath` (with the `vfs` feature), an
+/// in-memory `HashMap<String, String>` of paths to contents and a static table of
+/// paths and contents (see [generate_include_table]). Implement it to read
+/// templates from other storage, like embedded assets, archives or a database.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use ppx_impl::Preprocessor;
+/// let files = HashMap::from([
+///     ("lib/greeting.txt".to_string(), "#param NAME\nHello NAME!".to_string()),
+///     ("main.txt".to_string(), "#include \"lib/greeting.txt\" world".to_string()),
+/// ]);
+///
+/// let res = Preprocessor::with_loader(files)
+///     .build()
+///     .process_file("main.txt")
+///     .unwrap();
+/// assert_eq!(res.output, "Hello world!");
+/// ```
+pub trait SourceLoader {
+    /// Identifies a file or a directory.
+    type Path: Clone + PartialEq + std::fmt::Debug;
+
+    /// The directory includes are resolved in when no base directory was set.
+    fn root(&self) -> Self::Path;
+
+    /// Resolves `path`, as written in an `#include`, relative to the directory `dir`.
+    ///
+    /// Returns `None` if there is no file at that path.
+    fn resolve(&self, dir: &Self::Path, path: &str) -> Result<Option<Self::Path>>;
+
+    /// Reads the contents of the file at `path`.
+    fn read(&self, path: &Self::Path) -> Result<String>;
+
+    /// The directory containing `path`, used for resolving the includes of that file.
+    fn parent(&self, path: &Self::Path) -> Option<Self::Path>;
+
+    /// Resolves `path` to a unique path for the file it points to, so that a file can
+    /// be recognized no matter how it was included.
+    fn canonicalize(&self, path: &Self::Path) -> Self::Path {
+        return path.clone();
+    }
+
+    /// The path as shown in errors and `__FILE__`.
+    fn display(&self, path: &Self::Path) -> String;
+}
+
+/// Reads files with `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSystem;
+
+impl SourceLoader for FileSystem {
+    type Path = PathBuf;
+
+    fn root(&self) -> PathBuf {
+        return PathBuf::from(".");
+    }
+
+    fn resolve(&self, dir: &PathBuf, path: &str) -> Result<Option<PathBuf>> {
+        let file = dir.join(path);
+        return Ok(file.is_file().then_some(file));
+    }
+
+    fn read(&self, path: &PathBuf) -> Result<String> {
+        return read_to_string_std(path);
+    }
+
+    fn parent(&self, path: &PathBuf) -> Option<PathBuf> {
+        return path.parent().map(|dir| dir.to_path_buf());
+    }
+
+    fn canonicalize(&self, path: &PathBuf) -> PathBuf {
+        return std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+    }
+
+    fn display(&self, path: &PathBuf) -> String {
+        return path.to_string_lossy().into_owned();
+    }
+}
+
+/// Reads files from a virtual filesystem. Includes are resolved relative to this path
+/// when no base directory was set.
+///
+/// `VfsPath::join` already removes `.` and `..` components, so paths are canonical.
+#[cfg(feature = "vfs")]
+impl SourceLoader for VfsPath {
+    type Path = VfsPath;
+
+    fn root(&self) -> VfsPath {
+        return self.clone();
+    }
+
+    fn resolve(&self, dir: &VfsPath, path: &str) -> Result<Option<VfsPath>> {
+        // A path that can't be joined, like `..` at the root, doesn't exist
+        let Ok(file) = dir.join(path) else {
+            return Ok(None);
+        };
+        return Ok(file.is_file()?.then_some(file));
+    }
+
+    fn read(&self, path: &VfsPath) -> Result<String> {
+        let mut result = String::new();
+        path.open_file()?.read_to_string(&mut result)
+            .map_err(|err| ErrorKind::IOError(err, path.as_str().into()))?;
+        return Ok(result);
+    }
+
+    fn parent(&self, path: &VfsPath) -> Option<VfsPath> {
+        return (!path.is_root()).then(|| path.parent());
+    }
+
+    fn display(&self, path: &VfsPath) -> String {
+        return path.as_str().to_string();
+    }
+}
+
+/// Reads files from memory. Keys are paths separated by `/`, relative to the root,
+/// like `lib/lighting.wgsl`. `.` and `..` components in keys and paths are resolved,
+/// so `./lib/lighting.wgsl` is the same file.
+impl SourceLoader for HashMap<String, String> {
+    type Path = String;
+
+    fn root(&self) -> String {
+        return String::new();
+    }
+
+    fn resolve(&self, dir: &String, path: &str) -> Result<Option<String>> {
+        return Ok(join_key(dir, path).filter(|file| self.contains_key(file) || self.keys().any(|key| is_key(key, file))));
+    }
+
+    fn read(&self, path: &String) -> Result<String> {
+        let file = join_key("", path);
+        return self.get(path)
+            .or_else(|| self.iter().find(|(key, _)| file.as_deref().is_some_and(|file| is_key(key, file))).map(|(_, contents)| contents))
+            .cloned()
+            .ok_or_else(|| ErrorKind::IOError(std::io::ErrorKind::NotFound.into(), path.into()).into());
+    }
+
+    fn parent(&self, path: &String) -> Option<String> {
+        return parent_key(path);
+    }
+
+    fn canonicalize(&self, path: &String) -> String {
+        return join_key("", path).unwrap_or_else(|| path.clone());
+    }
+
+    fn display(&self, path: &String) -> String {
+        return path.clone();
+    }
+}
+
+/// Reads files from a table of paths and contents, like one embedded in the binary
+/// with [generate_include_table]. Paths are resolved like for `HashMap<String, String>`.
+impl<'s> SourceLoader for &'s [(&'s str, &'s str)] {
+    type Path = String;
+
+    fn root(&self) -> String {
+        return String::new();
+    }
+
+    fn resolve(&self, dir: &String, path: &str) -> Result<Option<String>> {
+        return Ok(join_key(dir, path).filter(|file| self.iter().any(|(key, _)| is_key(key, file))));
+    }
+
+    fn read(&self, path: &String) -> Result<String> {
+        let file = join_key("", path);
+        return self.iter()
+            .find(|(key, _)| file.as_deref().is_some_and(|file| is_key(key, file)))
+            .map(|(_, contents)| contents.to_string())
+            .ok_or_else(|| ErrorKind::IOError(std::io::ErrorKind::NotFound.into(), path.into()).into());
+    }
+
+    fn parent(&self, path: &String) -> Option<String> {
+        return parent_key(path);
+    }
+
+    fn canonicalize(&self, path: &String) -> String {
+        return join_key("", path).unwrap_or_else(|| path.clone());
+    }
+
+    fn display(&self, path: &String) -> String {
+        return path.clone();
+    }
+}
+
+/// Generates the Rust source of a table with every file in `dir`, for use in a build script.
+///
+/// The table is an expression of type `&[(&str, &str)]` pairing the path of each file,
+/// relative to `dir` and separated by `/`, with its contents embedded by `include_str!`.
+/// Use it as a [SourceLoader] to process templates without the directory at runtime.
+///
+/// Only UTF-8 text files are embedded. Hidden files and directories, whose name starts
+/// with `.` (like `.DS_Store`), and symbolic links to directories are skipped.
+///
+/// # Example
+///
+/// In `build.rs`:
+///
+/// ```rust,no_run
+/// let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+/// let table = ppx_impl::generate_include_table("templates").unwrap();
+/// std::fs::write(out_dir.join("templates.rs"), table).unwrap();
+/// println!("cargo::rerun-if-changed=templates");
+/// ```
+///
+/// In the crate:
+///
+/// ```rust,ignore
+/// static TEMPLATES: &[(&str, &str)] = include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+///
+/// let res = ppx_impl::Preprocessor::with_loader(TEMPLATES)
+///     .parameter("64")
+///     .build()
+///     .process_file("main.wgsl")?;
+/// ```
+pub fn generate_include_table(dir: impl AsRef<Path>) -> std::io::Result<String> {
+    fn list_files(dir: &Path, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            // Symbolic links to directories aren't followed, they could form a loop
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                list_files(&path, out)?;
+            } else if std::fs::metadata(&path).is_ok_and(|metadata| metadata.is_file())
+                && std::str::from_utf8(&std::fs::read(&path)?).is_ok()
+            {
+                out.push(path);
+            }
+        }
+        return Ok(());
+    }
+
+    let dir = std::fs::canonicalize(dir)?;
+    let mut files = vec![];
+    list_files(&dir, &mut files)?;
+    files.sort();
+
+    let mut out = String::from("&[\n");
+    for file in files {
+        let key = file.strip_prefix(&dir).unwrap_or(&file)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        out += &format!("    ({:?}, include_str!({:?})),\n", key, file.to_string_lossy());
+    }
+    out += "]";
+
+    return Ok(out);
+}
+
+/// Whether the table key `key` refers to the resolved path `path`.
+fn is_key(key: &str, path: &str) -> bool {
+    return join_key("", key).as_deref() == Some(path);
+}
+
+/// The directory containing `path` in an in-memory include map, `None` for the root.
+fn parent_key(path: &str) -> Option<String> {
+    if path.is_empty() {
+        return None;
+    }
+    return Some(path.rsplit_once('/').map(|(dir, _)| dir.to_string()).unwrap_or_default());
+}
+
+/// Joins `path` to the directory `dir` of an in-memory include map, removing `.` and
+/// `..` components. A path starting with `/` is relative to the root.
+///
+/// Returns `None` if the path leaves the root.
+fn join_key(dir: &str, path: &str) -> Option<String> {
+    let dir = if path.starts_with('/') { "" } else { dir };
+
+    let mut components: Vec<&str> = vec![];
+    for component in dir.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {},
+            ".." => {
+                components.pop()?;
+            },
+            _ => components.push(component),
+        }
+    }
+
+    return Some(components.join("/"));
+}
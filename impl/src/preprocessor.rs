@@ -0,0 +1,310 @@
+//! A reusable, configurable preprocessor.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::{DEFAULT_MAX_INCLUDE_DEPTH, FileSystem, Options, Output, Parameters, Result, SourceLoader};
+use crate::{check_predefined, parse_string_cow_impl};
+
+/// Processes templates with a fixed set of options.
+///
+/// A `Preprocessor` is created with [Preprocessor::builder] and can be used for any
+/// number of templates. Every template is processed independently, so macros
+/// defined in one are not visible in the next.
+///
+/// Files are read with the [SourceLoader] `L`, see [Preprocessor::with_loader].
+///
+/// # Example
+///
+/// ```rust
+/// # use ppx_impl::Preprocessor;
+/// let preprocessor = Preprocessor::builder()
+///     .base_dir("tests")
+///     .parameter("world")
+///     .build();
+///
+/// let res = preprocessor.process_str("#param A\nHello A!").unwrap();
+/// assert_eq!(res.output, "Hello world!");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Preprocessor<L: SourceLoader = FileSystem> {
+    loader: L,
+    base_dir: L::Path,
+    include_dirs: Vec<L::Path>,
+    parameters: Vec<String>,
+    named_parameters: Vec<(String, String)>,
+    max_include_depth: usize,
+    sandboxed: bool,
+    recover: bool,
+    replacements: Vec<(String, String)>,
+    fn_replacements: Vec<(String, Vec<String>, String)>,
+}
+
+impl Preprocessor {
+    /// Configures a preprocessor that reads files from the file system.
+    pub fn builder() -> PreprocessorBuilder {
+        return Preprocessor::with_loader(FileSystem);
+    }
+
+    /// Configures a preprocessor that reads includes from memory instead of from disk.
+    ///
+    /// `includes` maps paths to the contents of the files. Paths are separated by `/`
+    /// and resolved like on the file system: `#include "file"` is looked up next to the
+    /// including file and then from the root, `..` refers to the parent directory and
+    /// a leading `/` to the root.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use ppx_impl::Preprocessor;
+    /// let res = Preprocessor::with_includes([
+    ///     ("lib/consts.wgsl", "#include \"util.wgsl\"\nconst PI: f32 = 3.14;"),
+    ///     ("./lib/util.wgsl", "// util\n"),
+    /// ])
+    ///     .build()
+    ///     .process_str("#include \"lib/consts.wgsl\"")
+    ///     .unwrap();
+    /// assert_eq!(res.output, "// util\nconst PI: f32 = 3.14;");
+    /// ```
+    pub fn with_includes(
+        includes: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> PreprocessorBuilder<HashMap<String, String>> {
+        let includes = includes.into_iter()
+            .map(|(path, contents)| (path.into(), contents.into()))
+            .collect();
+        return Preprocessor::with_loader(includes);
+    }
+}
+
+impl<L: SourceLoader> Preprocessor<L> {
+    /// Configures a preprocessor that reads files with `loader`.
+    ///
+    /// The base directory defaults to [SourceLoader::root].
+    pub fn with_loader(loader: L) -> PreprocessorBuilder<L> {
+        return PreprocessorBuilder {
+            preprocessor: Preprocessor {
+                base_dir: loader.root(),
+                loader,
+                include_dirs: vec![],
+                parameters: vec![],
+                named_parameters: vec![],
+                max_include_depth: DEFAULT_MAX_INCLUDE_DEPTH,
+                sandboxed: false,
+                recover: false,
+                replacements: vec![],
+                fn_replacements: vec![],
+            },
+        };
+    }
+
+    /// Processes `input`.
+    pub fn process_str(&self, input: &str) -> Result<Output> {
+        return self.process(input, None);
+    }
+
+    /// Reads and processes `input_file`.
+    pub fn process_file(&self, input_file: impl Into<L::Path>) -> Result<Output> {
+        let input_file = input_file.into();
+        let content = self.loader.read(&input_file)?;
+
+        return self.process(&content, Some(input_file));
+    }
+
+    fn process(&self, input: &str, file: Option<L::Path>) -> Result<Output> {
+        check_predefined(&self.replacements, &self.fn_replacements)?;
+
+        let options = Options {
+            loader: &self.loader,
+            base_dir: &self.base_dir,
+            include_dirs: &self.include_dirs,
+            max_include_depth: self.max_include_depth,
+            sandboxed: self.sandboxed,
+            recover: self.recover,
+            replacements: &self.replacements,
+            fn_replacements: &self.fn_replacements,
+        };
+        let mut positional = self.parameters.iter().map(|param| Cow::Borrowed(param.as_str()));
+        let named = self.named_parameters.iter()
+            .map(|(name, value)| (name.clone(), Cow::Borrowed(value.as_str())))
+            .collect();
+        return parse_string_cow_impl(input, file, &options, &mut Parameters::new(&mut positional, named));
+    }
+}
+
+/// Configures a [Preprocessor].
+#[derive(Debug, Clone)]
+pub struct PreprocessorBuilder<L: SourceLoader = FileSystem> {
+    preprocessor: Preprocessor<L>,
+}
+
+impl<L: SourceLoader> PreprocessorBuilder<L> {
+    /// Includes are resolved relative to this directory when they aren't found next
+    /// to the including file. Defaults to [SourceLoader::root], the current directory
+    /// for the file system.
+    pub fn base_dir(mut self, base_dir: impl Into<L::Path>) -> Self {
+        self.preprocessor.base_dir = base_dir.into();
+        return self;
+    }
+
+    /// Adds a directory that is searched for `#include <file>`, and for `#include "file"`
+    /// after the directory of the including file and the base directory.
+    ///
+    /// Directories are searched in the order they were added.
+    pub fn include_dir(mut self, include_dir: impl Into<L::Path>) -> Self {
+        self.preprocessor.include_dirs.push(include_dir.into());
+        return self;
+    }
+
+    /// Adds multiple include directories, see [include_dir](Self::include_dir).
+    pub fn include_dirs(mut self, include_dirs: impl IntoIterator<Item = impl Into<L::Path>>) -> Self {
+        self.preprocessor.include_dirs.extend(include_dirs.into_iter().map(|dir| dir.into()));
+        return self;
+    }
+
+    /// Adds the value of the next `#param` of the processed template.
+    pub fn parameter(mut self, value: impl Into<String>) -> Self {
+        self.preprocessor.parameters.push(value.into());
+        return self;
+    }
+
+    /// Adds the values of the next `#param`s of the processed template.
+    pub fn parameters(mut self, values: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.preprocessor.parameters.extend(values.into_iter().map(|value| value.into()));
+        return self;
+    }
+
+    /// Defines a substitution for every processed file and the files it includes, like
+    /// `#define NAME value` at the top of the template.
+    ///
+    /// Processing fails with [ErrorKind::InvalidMacro](crate::ErrorKind::InvalidMacro) if `name`
+    /// isn't an identifier.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use ppx_impl::Preprocessor;
+    /// let res = Preprocessor::builder()
+    ///     .define("MAX_LIGHTS", "16")
+    ///     .define("PLATFORM_WEB", "")
+    ///     .build()
+    ///     .process_str("#ifdef PLATFORM_WEB\nlights: MAX_LIGHTS\n#endif")
+    ///     .unwrap();
+    /// assert_eq!(res.output, "lights: 16\n");
+    /// ```
+    pub fn define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.preprocessor.replacements.push((name.into(), value.into()));
+        return self;
+    }
+
+    /// Defines a function-like macro for every processed file and the files it includes,
+    /// like `#define NAME(params) body` at the top of the template.
+    ///
+    /// A last parameter of `...` makes the macro variadic. Processing fails like for `#define`
+    /// if `name` or a parameter isn't an identifier.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use ppx_impl::Preprocessor;
+    /// let res = Preprocessor::builder()
+    ///     .define_fn("SQUARE", ["x"], "((x) * (x))")
+    ///     .build()
+    ///     .process_str("SQUARE(2)")
+    ///     .unwrap();
+    /// assert_eq!(res.output, "((2) * (2))");
+    /// ```
+    pub fn define_fn(
+        mut self,
+        name: impl Into<String>,
+        params: impl IntoIterator<Item = impl Into<String>>,
+        body: impl Into<String>,
+    ) -> Self {
+        let params = params.into_iter().map(|param| param.into()).collect();
+        self.preprocessor.fn_replacements.push((name.into(), params, body.into()));
+        return self;
+    }
+
+    /// Sets the value of `#param name` of the processed template.
+    ///
+    /// `#param`s without a named value take the next positional [parameter](Self::parameter).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use ppx_impl::Preprocessor;
+    /// let res = Preprocessor::builder()
+    ///     .named_parameter("NAME", "world")
+    ///     .named_parameter("GREETING", "Hello")
+    ///     .build()
+    ///     .process_str("#param GREETING\n#param NAME\nGREETING NAME!")
+    ///     .unwrap();
+    /// assert_eq!(res.output, "Hello world!");
+    /// ```
+    pub fn named_parameter(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        self.preprocessor.named_parameters.retain(|(n, _)| *n != name);
+        self.preprocessor.named_parameters.push((name, value.into()));
+        return self;
+    }
+
+    /// Sets the values of multiple `#param`s by name, for example from a `HashMap`.
+    pub fn named_parameters(self, values: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>) -> Self {
+        return values.into_iter().fold(self, |builder, (name, value)| builder.named_parameter(name, value));
+    }
+
+    /// The maximum depth includes can be nested. Defaults to [DEFAULT_MAX_INCLUDE_DEPTH].
+    pub fn max_include_depth(mut self, max_include_depth: usize) -> Self {
+        self.preprocessor.max_include_depth = max_include_depth;
+        return self;
+    }
+
+    /// Restricts includes to the base directory and the include directories, for
+    /// processing untrusted templates.
+    ///
+    /// Includes with an absolute path, or that resolve to a file outside of these
+    /// directories (through `..` or a symbolic link), fail with [ErrorKind::IncludeOutsideRoot](crate::ErrorKind::IncludeOutsideRoot).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use ppx_impl::{ErrorKind, Preprocessor};
+    /// let res = Preprocessor::builder()
+    ///     .base_dir("tests/include")
+    ///     .sandboxed(true)
+    ///     .build()
+    ///     .process_str("#include \"../test.txt\"");
+    /// assert!(matches!(res.unwrap_err().kind(), ErrorKind::IncludeOutsideRoot(..)));
+    /// ```
+    pub fn sandboxed(mut self, sandboxed: bool) -> Self {
+        self.preprocessor.sandboxed = sandboxed;
+        return self;
+    }
+
+    /// Keeps going after an error instead of failing: the line with the error is
+    /// skipped, and the error is added to [Output::errors](crate::Output::errors).
+    ///
+    /// This reports all invalid directives, parameter names and conditions of a
+    /// template at once, together with the output of the lines without errors.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use ppx_impl::{ErrorKind, Preprocessor};
+    /// let res = Preprocessor::builder()
+    ///     .recover(true)
+    ///     .build()
+    ///     .process_str("#inclde \"a.txt\"\nvalid\n#define F(1) x")
+    ///     .unwrap();
+    /// assert_eq!(res.output, "valid\n");
+    /// assert!(matches!(res.errors[0].kind(), ErrorKind::InvalidMacro(_)));
+    /// assert!(matches!(res.errors[1].kind(), ErrorKind::InvalidParameterName(_)));
+    /// ```
+    pub fn recover(mut self, recover: bool) -> Self {
+        self.preprocessor.recover = recover;
+        return self;
+    }
+
+    pub fn build(self) -> Preprocessor<L> {
+        return self.preprocessor;
+    }
+}
@@ -1,4 +1,4 @@
-use ppx_impl::parse_string;
+use ppx_impl::{parse_string, Error, ErrorKind, Preprocessor, SourceLocation, DEFAULT_MAX_INCLUDE_DEPTH};
 
 #[test]
 fn test_define() {
@@ -46,6 +46,34 @@ TEST(world, hello)
     );
 }
 
+#[test]
+fn test_undef() {
+    let res = parse_string("
+#define A 1
+A
+#undef A
+A
+",
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "1\nA");
+}
+
+#[test]
+fn test_undef_fn() {
+    let res = parse_string("
+#define TEST(a) a a
+TEST(b)
+#undef TEST
+TEST(b)
+",
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "b b\nTEST(b)");
+}
+
 #[test]
 fn test_param() {
     assert_eq!(
@@ -93,10 +121,368 @@ hello world
     )
 }
 
+#[test]
+fn test_include_dirs() {
+    let res = Preprocessor::builder()
+        .base_dir("tests")
+        .include_dir("tests/include")
+        .build()
+        .process_str("#include \"test.txt\"\n#include <test.txt>\n#include \"shared.txt\"")
+        .unwrap();
+    assert_eq!(res.output.trim(), "Included from test.txt!\nIncluded from include/test.txt!\nShared");
+}
+
+#[test]
+fn test_include_system_not_found() {
+    let res = Preprocessor::builder()
+        .base_dir("tests")
+        .build()
+        .process_str("#include <test.txt>");
+    match res.as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::IncludeNotFound(path), Some(location))) => {
+            assert_eq!(path, "test.txt");
+            assert_eq!(*location, SourceLocation { file: None, line: 1, column: 1 });
+        },
+        _ => panic!("Expected IncludeNotFound error"),
+    }
+}
+
+#[test]
+fn test_include_relative_to_including_file() {
+    let res = parse_string(r#"
+#include "lib/lighting/pbr.txt"
+"#,
+        std::env::current_dir().unwrap().join("tests"),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "brdf\nIncluded from test.txt!");
+}
+
+#[test]
+fn test_include_cycle() {
+    let res = parse_string(r#"#include "cycle/a.txt""#, std::env::current_dir().unwrap().join("tests"), std::iter::empty());
+    match res.as_ref().map_err(Error::kind) {
+        Err(kind @ ErrorKind::IncludeCycle(_)) => assert_eq!(kind.to_string(), "Include cycle: cycle/a.txt -> b.txt -> a.txt"),
+        _ => panic!("Expected IncludeCycle error"),
+    }
+}
+
+#[test]
+fn test_include_cycle_guarded() {
+    let res = parse_string(r#"
+#include "guarded_a.txt"
+#include "once.txt"
+"#,
+        std::env::current_dir().unwrap().join("tests/cycle"),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.split_whitespace().collect::<Vec<_>>(), ["A", "B", "ONCE"]);
+}
+
+#[test]
+fn test_max_include_depth() {
+    let files = (0..5).map(|i| (format!("{i}.txt"), format!("#include \"{}.txt\"", i + 1))).collect::<std::collections::HashMap<_, _>>();
+    let res = Preprocessor::with_loader(files)
+        .max_include_depth(3)
+        .build()
+        .process_file("0.txt");
+    match res.as_ref().map_err(Error::kind) {
+        Err(ErrorKind::IncludeDepthExceeded(3, stack)) => assert_eq!(*stack, ["0.txt", "1.txt", "2.txt", "3.txt", "4.txt"]),
+        _ => panic!("Expected IncludeDepthExceeded error"),
+    }
+}
+
+#[test]
+fn test_include_cycle_with_define() {
+    // A file that includes itself after redefining the same macro doesn't change anything by doing so
+    let res = Preprocessor::builder()
+        .base_dir("tests")
+        .build()
+        .process_str(r#"#include "cycle/deep.txt""#);
+    match res.as_ref().map_err(Error::kind) {
+        Err(kind @ ErrorKind::IncludeCycle(_)) => assert_eq!(kind.to_string(), "Include cycle: deep.txt -> deep.txt"),
+        _ => panic!("Expected IncludeCycle error"),
+    }
+}
+
+#[test]
+fn test_include_recursion_bounded_by_builtins() {
+    let res = Preprocessor::with_includes([("rec.txt", "#if __INCLUDE_LEVEL__ < 3\n__INCLUDE_LEVEL__\n#include \"rec.txt\"\n#endif")])
+        .build()
+        .process_file("rec.txt")
+        .unwrap();
+    assert_eq!(res.output.split_whitespace().collect::<Vec<_>>(), ["0", "1", "2"]);
+
+    let res = Preprocessor::with_includes([("rec.txt", "#if __COUNTER__ < 3\nx\n#include \"rec.txt\"\n#endif")])
+        .build()
+        .process_file("rec.txt")
+        .unwrap();
+    assert_eq!(res.output.split_whitespace().collect::<Vec<_>>(), ["x", "x", "x"]);
+
+    let res = Preprocessor::with_includes([("rec.txt", "__COUNTER__\n#include \"rec.txt\"")])
+        .max_include_depth(3)
+        .build()
+        .process_file("rec.txt");
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::IncludeDepthExceeded(3, _))));
+}
+
+#[test]
+fn test_default_max_include_depth_stack() {
+    // Nesting includes up to the default depth doesn't overflow a 2 MB stack in a debug build
+    let res = std::thread::Builder::new().stack_size(2 * 1024 * 1024).spawn(|| {
+        let files = (0..=DEFAULT_MAX_INCLUDE_DEPTH + 1)
+            .map(|i| (format!("{i}.txt"), format!("#define X{i}\n#include \"{}.txt\"", i + 1)))
+            .collect::<std::collections::HashMap<_, _>>();
+        let deep = Preprocessor::with_loader(files).build().process_file("0.txt");
+        assert!(matches!(deep.as_ref().map_err(Error::kind), Err(ErrorKind::IncludeDepthExceeded(DEFAULT_MAX_INCLUDE_DEPTH, _))), "{deep:?}");
+
+        let cycle = Preprocessor::with_includes([("self.txt", "#define X 1\n#include \"self.txt\"")]).build().process_file("self.txt");
+        assert!(matches!(cycle.as_ref().map_err(Error::kind), Err(ErrorKind::IncludeCycle(_))), "{cycle:?}");
+    }).unwrap().join();
+    assert!(res.is_ok());
+}
+
+#[test]
+fn test_include_with_named_params() {
+    let res = parse_string(r#"
+#include "test_with_param.txt" B=world, A=hello
+"#,
+        std::env::current_dir().unwrap().join("tests"),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "hello world");
+
+    let res = parse_string(r#"#include "test_with_param.txt" A=1, B=2, C=3"#, std::env::current_dir().unwrap().join("tests"), std::iter::empty());
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::UnknownParameter(name)) if name == "C"));
+
+    let res = parse_string(r#"#include "test_with_param.txt" A=1"#, std::env::current_dir().unwrap().join("tests"), std::iter::empty());
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::MissingParameter(name)) if name == "B"));
+
+    let res = parse_string(r#"#include "test_with_param.txt" A=1, foo"#, std::env::current_dir().unwrap().join("tests"), std::iter::empty());
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::MixedParameters)));
+
+    let res = parse_string(r#"#include "test_with_param.txt" a==b,c"#, std::env::current_dir().unwrap().join("tests"), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "a==b c");
+}
+
+#[test]
+fn test_named_params() {
+    let params = std::collections::HashMap::from([("B", "world"), ("A", "hello")]);
+    let res = Preprocessor::builder()
+        .named_parameters(params)
+        .build()
+        .process_file("tests/test_with_param.txt")
+        .unwrap();
+    assert_eq!(res.output.trim(), "hello world");
+
+    let res = Preprocessor::builder()
+        .named_parameter("B", "world")
+        .parameter("hello")
+        .build()
+        .process_file("tests/test_with_param.txt")
+        .unwrap();
+    assert_eq!(res.output.trim(), "hello world");
+}
+
+#[test]
+fn test_param_default() {
+    let input = "#param A = 64\n#param B=two words\nA B";
+    let res = parse_string(input, std::env::current_dir().unwrap(), std::iter::empty::<&str>()).unwrap();
+    assert_eq!(res, "64 two words");
+
+    let res = parse_string(input, std::env::current_dir().unwrap(), ["32"].into_iter()).unwrap();
+    assert_eq!(res, "32 two words");
+
+    let res = Preprocessor::builder()
+        .named_parameter("B", "one")
+        .build()
+        .process_str(input)
+        .unwrap();
+    assert_eq!(res.output, "64 one");
+}
+
+#[test]
+fn test_in_memory_loader() {
+    let files = std::collections::HashMap::from([
+        ("main.txt".to_string(), "#include \"lib/a.txt\"\n#include <b.txt>".to_string()),
+        ("lib/a.txt".to_string(), "#include \"../shared.txt\"".to_string()),
+        ("include/b.txt".to_string(), "#include \"/shared.txt\"".to_string()),
+        ("shared.txt".to_string(), "__FILE__\n".to_string()),
+    ]);
+
+    let res = Preprocessor::with_loader(files)
+        .include_dir("include")
+        .build()
+        .process_file("main.txt")
+        .unwrap();
+    assert_eq!(res.output, "\"../shared.txt\"\n\"/shared.txt\"\n");
+}
+
+#[test]
+fn test_in_memory_loader_normalizes_paths() {
+    let files = std::collections::HashMap::from([
+        ("./a/main.txt".to_string(), "#pragma once\n#include \"lib.txt\"\n#include \"../a/main.txt\"".to_string()),
+        ("a/./lib.txt".to_string(), "lib".to_string()),
+    ]);
+
+    let preprocessor = Preprocessor::with_loader(files).build();
+    assert_eq!(preprocessor.process_file("./a/main.txt").unwrap().output, "lib");
+    assert_eq!(preprocessor.process_file("a/main.txt").unwrap().output, "lib");
+}
+
+#[test]
+fn test_static_include_table() {
+    static TEMPLATES: &[(&str, &str)] = &[
+        ("main.txt", "#param N = 4\n#include \"lib/a.txt\" N"),
+        ("./lib/a.txt", "#param COUNT\ncount: COUNT"),
+    ];
+
+    let res = Preprocessor::with_loader(TEMPLATES)
+        .parameter("64")
+        .build()
+        .process_file("main.txt")
+        .unwrap();
+    assert_eq!(res.output, "count: 64");
+}
+
+#[test]
+fn test_generate_include_table() {
+    let dir = std::fs::canonicalize("tests/lib").unwrap();
+    let table = ppx_impl::generate_include_table("tests/lib").unwrap();
+    assert_eq!(table, format!(
+        "&[\n    (\"lighting/brdf.txt\", include_str!({:?})),\n    (\"lighting/pbr.txt\", include_str!({:?})),\n]",
+        dir.join("lighting/brdf.txt").to_string_lossy(),
+        dir.join("lighting/pbr.txt").to_string_lossy(),
+    ));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_generate_include_table_skips_other_files() {
+    let dir = std::env::temp_dir().join(format!("ppx-include-table-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("lib/.git")).unwrap();
+    std::fs::write(dir.join("lib/a.txt"), "a").unwrap();
+    std::fs::write(dir.join("lib/.git/HEAD"), "ref").unwrap();
+    std::fs::write(dir.join(".DS_Store"), "store").unwrap();
+    std::fs::write(dir.join("image.bin"), [0xff, 0xfe, 0x00]).unwrap();
+    std::os::unix::fs::symlink(&dir, dir.join("lib/loop")).unwrap();
+    std::os::unix::fs::symlink(dir.join("lib/a.txt"), dir.join("b.txt")).unwrap();
+
+    let table = ppx_impl::generate_include_table(&dir);
+    let dir = std::fs::canonicalize(&dir).unwrap();
+    assert_eq!(table.unwrap(), format!(
+        "&[\n    (\"b.txt\", include_str!({:?})),\n    (\"lib/a.txt\", include_str!({:?})),\n]",
+        dir.join("b.txt").to_string_lossy(),
+        dir.join("lib/a.txt").to_string_lossy(),
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sandboxed_includes() {
+    let preprocessor = Preprocessor::builder()
+        .base_dir("tests/include")
+        .include_dir("tests/lib")
+        .sandboxed(true)
+        .build();
+
+    let res = preprocessor.process_str("#include \"shared.txt\"\n#include <lighting/brdf.txt>").unwrap();
+    assert!(res.output.starts_with("Shared"));
+
+    for include in ["../test.txt", "../include/../test.txt", "/etc/passwd"] {
+        match preprocessor.process_str(&format!("#include \"{include}\"")).as_ref().map_err(Error::kind) {
+            Err(ErrorKind::IncludeOutsideRoot(path)) => assert_eq!(path, include),
+            res => panic!("Expected IncludeOutsideRoot error for {include}, got {res:?}"),
+        }
+    }
+
+    let res = Preprocessor::builder()
+        .base_dir("tests/include")
+        .build()
+        .process_str("#include \"../test.txt\"");
+    assert!(res.is_ok());
+}
+
+#[test]
+fn test_error_location_in_include() {
+    let files = std::collections::HashMap::from([
+        ("main.txt".to_string(), "#define A 1\n  #include \"lib/a.txt\"".to_string()),
+        ("lib/a.txt".to_string(), "a\n\n    #pragma twice".to_string()),
+    ]);
+
+    let err = Preprocessor::with_loader(files).build().process_file("main.txt").unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::InvalidPragma(_)));
+    assert_eq!(err.location(), Some(&SourceLocation { file: Some("lib/a.txt".to_string()), line: 3, column: 5 }));
+    assert_eq!(err.include_stack(), [SourceLocation { file: Some("main.txt".to_string()), line: 2, column: 3 }]);
+    assert_eq!(err.to_string(), "\
+Invalid pragma 'twice'
+ --> lib/a.txt:3:5
+  |
+3 |     #pragma twice
+  |     ^^^^^^^^^^^^^
+  = note: included from main.txt:2:3");
+}
+
+#[test]
+fn test_error_location_in_line() {
+    let err = parse_string("#define F(a) a\nsome text F(1, 2)", std::env::current_dir().unwrap(), std::iter::empty()).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::UnusedParametersMacro(name) if name == "F"));
+    assert_eq!(err.location(), Some(&SourceLocation { file: None, line: 2, column: 11 }));
+    assert_eq!(err.to_string(), "\
+Too many parameters passed to function-like macro `F`
+ --> <input>:2:11
+  |
+2 | some text F(1, 2)
+  |           ^");
+
+    let err = parse_string("#define TWO 2\n#if TWO + 1 > (1 / 0)", std::env::current_dir().unwrap(), std::iter::empty()).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::InvalidCondition(_, message) if message == "division by zero"));
+    assert_eq!(err.location(), Some(&SourceLocation { file: None, line: 2, column: 18 }));
+    assert!(err.to_string().ends_with("2 | #if TWO + 1 > (1 / 0)\n  |                  ^"), "{err}");
+}
+
+#[test]
+fn test_recover_from_errors() {
+    let files = std::collections::HashMap::from([
+        ("main.txt".to_string(), "#define F(1) x\nbefore\n#include \"lib.txt\"\n#if defined(X\nskipped\n#else\nalso skipped\n#endif\nafter\n#include \"missing.txt\"".to_string()),
+        ("lib.txt".to_string(), "#pragma twice\nlib\n".to_string()),
+    ]);
+
+    let res = Preprocessor::with_loader(files)
+        .recover(true)
+        .build()
+        .process_file("main.txt")
+        .unwrap();
+    assert_eq!(res.output, "before\nlib\nafter\n");
+
+    let errors = res.errors.iter()
+        .map(|err| (err.location().map(|location| (location.file.as_deref().unwrap(), location.line)), err.include_stack().len()))
+        .collect::<Vec<_>>();
+    assert_eq!(errors, [
+        (Some(("main.txt", 1)), 0),
+        (Some(("lib.txt", 1)), 1),
+        (Some(("main.txt", 4)), 0),
+        (Some(("main.txt", 10)), 0),
+    ]);
+    assert!(matches!(res.errors[0].kind(), ErrorKind::InvalidParameterName(_)));
+    assert!(matches!(res.errors[1].kind(), ErrorKind::InvalidPragma(_)));
+    assert!(matches!(res.errors[3].kind(), ErrorKind::IncludeNotFound(_)));
+
+    let res = Preprocessor::builder()
+        .recover(true)
+        .parameters(["unused"])
+        .build()
+        .process_str("text")
+        .unwrap();
+    assert_eq!(res.output, "text");
+    assert!(matches!(res.errors[..], [ref err] if matches!(err.kind(), ErrorKind::UnusedParameters) && err.location().is_none()));
+}
+
 #[test]
 fn test_too_many_parameters() {
-    match parse_string("", std::env::current_dir().unwrap(), [""].into_iter()) {
-        Err(ppx_impl::Error::UnusedParameters) => {},
+    match parse_string("", std::env::current_dir().unwrap(), [""].into_iter()).as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::UnusedParameters, None)) => {},
         _ => panic!("Expected UnusedParameters error")
     }
 }
@@ -119,6 +505,149 @@ fn test_pragma_once() {
     assert_eq!(res.trim(), "HELLO");
 }
 
+#[test]
+fn test_pragma_once_canonical_path() {
+    let res = Preprocessor::builder()
+        .base_dir("tests")
+        .include_dir("tests/include/..")
+        .build()
+        .process_str(r#"
+#include "pragma_once.txt"
+#include "./pragma_once.txt"
+#include "include/../pragma_once.txt"
+#include <pragma_once.txt>
+"#)
+        .unwrap();
+    assert_eq!(res.output.trim(), "HELLO");
+}
+
+#[test]
+fn test_include_guard() {
+    let res = Preprocessor::builder()
+        .base_dir("tests")
+        .build()
+        .process_str(r#"
+#include "guard.txt"
+#include "guard.txt"
+#include "pragma_once.txt"
+#include "pragma_once.txt"
+#include "not_guard.txt"
+#include "not_guard.txt"
+"#)
+        .unwrap();
+    assert_eq!(res.output.split('\n').filter(|line| !line.is_empty()).collect::<Vec<_>>(), ["GUARDED", "HELLO", "NOT GUARDED", "NOT GUARDED"]);
+    assert_eq!(res.skipped_includes, 2);
+}
+
+#[test]
+fn test_variadic() {
+    let res = parse_string("#define LOG(level, ...) log(level, __VA_ARGS__)\nLOG(info, a, (b, c))", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "log(info, a, (b, c))");
+}
+
+#[test]
+fn test_variadic_only() {
+    let res = parse_string("#define FIELDS(...) struct S { __VA_ARGS__ }\nFIELDS(a: u32, b: f32)", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "struct S { a: u32, b: f32 }");
+}
+
+#[test]
+fn test_va_opt() {
+    let res = parse_string("
+#define CALL(f, ...) f(__VA_OPT__(0, ) __VA_ARGS__)
+CALL(a)
+CALL(b, 1, 2)
+", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "a( )\n b(0,  1, 2)");
+}
+
+#[test]
+fn test_variadic_not_enough_parameters() {
+    match parse_string("#define LOG(level, msg, ...) level msg\nLOG(info)", std::env::current_dir().unwrap(), std::iter::empty()).as_ref().map_err(Error::kind) {
+        Err(ErrorKind::NotEnoughParametersMacro(name)) => assert_eq!(name, "LOG"),
+        _ => panic!("Expected NotEnoughParametersMacro error")
+    }
+}
+
+#[test]
+fn test_variadic_not_last() {
+    match parse_string("#define LOG(..., level) level", std::env::current_dir().unwrap(), std::iter::empty()).as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::InvalidParameterName(name), Some(SourceLocation { line: 1, .. }))) => assert_eq!(name, "..."),
+        _ => panic!("Expected InvalidParameterName error")
+    }
+}
+
+#[test]
+fn test_stringify() {
+    let res = parse_string("#define NAME_OF(x) #x\nNAME_OF(camera_uniform)", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), r#""camera_uniform""#);
+}
+
+#[test]
+fn test_stringify_escape() {
+    let res = parse_string(r#"
+#define LABEL(name, x) label(# name, x)
+LABEL(a "b" \c, x)
+"#, std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), r#"label("a \"b\" \\c", x)"#);
+}
+
+#[test]
+fn test_rescan_definition_order() {
+    let res = parse_string("
+#define INNER(x) [x]
+#define OUTER(x) INNER(x) SIZE
+#define SIZE 4
+OUTER(a)
+", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "[a] 4");
+}
+
+#[test]
+fn test_rescan_arguments() {
+    let res = parse_string("
+#define A 1
+#define TWICE(x) x x
+#define WRAP(x) (x)
+TWICE(WRAP(A))
+", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "(1)  (1)");
+}
+
+#[test]
+fn test_rescan_recursion() {
+    let res = parse_string("
+#define A B + A
+#define B A
+#define f(x) f(x + 1)
+A
+f(0)
+", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "A + A\n f(0 + 1)");
+}
+
+#[test]
+fn test_rescan_paste() {
+    let res = parse_string("
+#define CAT(a, b) a##b
+#define AB done
+CAT(A, B)
+", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "done");
+}
+
+#[test]
+fn test_paste_around_macro_call_in_text() {
+    let res = parse_string("#define T(a) a\npre##T(x)##post", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res, "pre xpost");
+}
+
+#[test]
+fn test_arguments_are_not_substituted_twice() {
+    let res = parse_string("#define SWAP(a, b) b a\nSWAP(b, a)", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res.trim(), "a b");
+}
+
 #[test]
 fn test_paste_left() {
     let res = parse_string("#define TEST(a) b##a\nTEST(c)", std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
@@ -208,6 +737,174 @@ NOK
     assert_eq!(res.trim(), "OK");
 }
 
+#[test]
+fn test_unterminated_conditional() {
+    let res = parse_string("#ifdef A\n#else\n#if true\nB\n#endif\n", std::env::current_dir().unwrap(), std::iter::empty());
+    match res.as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::UnterminatedConditional, Some(location))) => assert_eq!(location.line, 1),
+        res => panic!("Expected UnterminatedConditional error, got {res:?}"),
+    }
+}
+
+#[test]
+fn test_conditional_without_if() {
+    for (input, directive) in [("#else", "else"), ("A\n#elif true", "elif")] {
+        match parse_string(input, std::env::current_dir().unwrap(), std::iter::empty()).as_ref().map_err(Error::kind) {
+            Err(ErrorKind::ElseWithoutIf(d)) => assert_eq!(*d, directive),
+            res => panic!("Expected ElseWithoutIf error, got {res:?}"),
+        }
+    }
+
+    let res = parse_string("#if true\n#endif\n#endif", std::env::current_dir().unwrap(), std::iter::empty());
+    match res.as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::EndifWithoutIf, Some(location))) => assert_eq!(location.line, 3),
+        res => panic!("Expected EndifWithoutIf error, got {res:?}"),
+    }
+}
+
+#[test]
+fn test_conditional_across_include() {
+    let files = std::collections::HashMap::from([
+        ("open.txt".to_string(), "#ifdef A\na".to_string()),
+        ("close.txt".to_string(), "#endif".to_string()),
+    ]);
+    let preprocessor = Preprocessor::with_loader(files).build();
+
+    match preprocessor.process_str("#include \"open.txt\"\n#endif").as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::UnterminatedConditional, Some(location))) => {
+            assert_eq!(location.file.as_deref(), Some("open.txt"));
+        },
+        res => panic!("Expected UnterminatedConditional error, got {res:?}"),
+    }
+
+    match preprocessor.process_str("#if true\n#include \"close.txt\"").as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::EndifWithoutIf, Some(location))) => {
+            assert_eq!(location.file.as_deref(), Some("close.txt"));
+        },
+        res => panic!("Expected EndifWithoutIf error, got {res:?}"),
+    }
+}
+
+#[test]
+fn test_condition_operators() {
+    let input = "#define FLAGS 0x6
+#define SIZE 64
+#if (FLAGS & 0b100) && SIZE >= 1 << 6
+a
+#endif
+#if 2 + 3 * 4 == 14 && -7 / 2 == -3 && 7 % 3 == 1 && ~0 == -1
+b
+#endif
+#if SIZE > 128 ? 0 : 010 == 8
+c
+#endif
+#if 5
+d
+#endif
+#if 1 - 1 || !1
+e
+#endif";
+    let res = parse_string(input, std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res, "a\nb\nc\nd\n");
+}
+
+#[test]
+fn test_condition_short_circuit() {
+    let input = "#define B 0
+#if B != 0 && 10 / B > 2
+a
+#elif B == 0 || UNDEFINED
+b
+#endif
+#if B ? 1 / B : 1
+c
+#endif";
+    let res = parse_string(input, std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res, "b\nc\n");
+}
+
+#[test]
+fn test_invalid_condition() {
+    for (input, expected, column) in [
+        ("#if 1 / 0", "division by zero", 7),
+        ("#if 1 + UNDEFINED", "unknown identifier `UNDEFINED`", 9),
+        ("#if (1 + 2", "expected `)` at the end of the condition", 11),
+        ("#if 1 +", "expected an expression at the end of the condition", 8),
+        ("#if 1 2", "unexpected `2`", 7),
+        ("#if 1.5 > 1", "floating point number `1.5` isn't allowed", 5),
+        ("#if 1 << 64", "shift by 64 is out of range", 7),
+        ("#if 9223372036854775807 + 1", "integer overflow", 25),
+    ] {
+        match parse_string(input, std::env::current_dir().unwrap(), std::iter::empty()).as_ref().map_err(|err| (err.kind(), err.location())) {
+            Err((ErrorKind::InvalidCondition(_, message), Some(location))) => assert_eq!((message.as_str(), location.column), (expected, column)),
+            res => panic!("Expected InvalidCondition error for {input:?}, got {res:?}"),
+        }
+    }
+}
+
+#[test]
+fn test_string_conditions() {
+    let input = r#"#param BACKEND
+#param FEATURES
+#if BACKEND == "vulkan" && BACKEND != "metal"
+a
+#endif
+#if contains(str(FEATURES), "shadows") && !contains(str(FEATURES), "bloom")
+b
+#endif
+#if starts_with(BACKEND, "vul") && ends_with(str(BACKEND), "kan") && len(BACKEND) == 6
+c
+#endif
+#if lower("VuLkAn") == BACKEND && upper(BACKEND) == "VULKAN" && "a\"b" != "a\\b"
+d
+#endif
+#if BACKEND == "metal" || contains(BACKEND, "")
+e
+#endif"#;
+    let res = parse_string(input, std::env::current_dir().unwrap(), ["vulkan", "shadows, fog"].into_iter()).unwrap();
+    assert_eq!(res, "a\nb\nc\nd\ne\n");
+}
+
+#[test]
+fn test_string_literals_in_conditions_are_not_expanded() {
+    let input = r#"#define M defined
+#define linux 1
+#define OS linux
+#if M == "defined" && contains(str(M), "x") == 0
+a
+#endif
+#if contains("M is defined here", "is defined here")
+b
+#endif
+#if OS == 1 && "linux" != "1" && str(OS) == "linux"
+c
+#endif"#;
+    let res = parse_string(input, std::env::current_dir().unwrap(), std::iter::empty()).unwrap();
+    assert_eq!(res, "a\nb\nc\n");
+}
+
+#[test]
+fn test_invalid_string_condition() {
+    for (input, expected, column) in [
+        (r#"#if "a""#, "expected an integer, found a string", 5),
+        (r#"#if "a" == 1"#, "expected a string, found an integer", 12),
+        (r#"#if len(1) > 0"#, "expected a string, found an integer", 9),
+        (r#"#if len("a", "b")"#, "`len` takes 1 argument, found 2", 5),
+        (r#"#if size("a")"#, "unknown function `size`", 5),
+        (r#"#if "a" "b""#, r#"unexpected `"b"`"#, 9),
+        (r#"#if "abc"#, "unterminated string", 5),
+        (r#"#if UNDEFINED > 1"#, "unknown identifier `UNDEFINED`", 5),
+        (r#"#if BACKNED == "vulkan""#, "unknown identifier `BACKNED`", 5),
+        (r#"#if contains("vulkan", vul)"#, "unknown identifier `vul`", 24),
+        (r#"#if str(UNDEFINED) == "a""#, "`str` takes the name of a substitution, found `UNDEFINED`", 5),
+    ] {
+        match parse_string(input, std::env::current_dir().unwrap(), std::iter::empty()).as_ref().map_err(|err| (err.kind(), err.location())) {
+            Err((ErrorKind::InvalidCondition(_, message), Some(location))) => assert_eq!((message.as_str(), location.column), (expected, column)),
+            res => panic!("Expected InvalidCondition error for {input:?}, got {res:?}"),
+        }
+    }
+}
+
 #[test]
 fn test_elif_after_else() {
     let res = parse_string(r#"
@@ -222,8 +919,28 @@ B
         std::env::current_dir().unwrap(),
         std::iter::empty()
     );
-    match res {
-        Err(ppx_impl::Error::ElifAfterElse) => {},
+    match res.as_ref().map_err(Error::kind) {
+        Err(ErrorKind::ElifAfterElse) => {},
+        _ => panic!("No error thrown")
+    }
+}
+
+#[test]
+fn test_else_after_else() {
+    let res = parse_string(r#"
+#if false
+A
+#else
+B
+#else
+C
+#endif
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    );
+    match res.as_ref().map_err(Error::kind) {
+        Err(ErrorKind::ElseAfterElse) => {},
         _ => panic!("No error thrown")
     }
 }
@@ -242,6 +959,296 @@ OK
     assert_eq!(res.trim(), "OK");
 }
 
+#[test]
+fn test_ifdef() {
+    let res = parse_string(r#"
+#define A
+#ifdef A
+OK
+#endif
+#ifdef B
+NOK
+#endif
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "OK");
+}
+
+#[test]
+fn test_ifndef() {
+    let res = parse_string(r#"
+#define TEST(a) a
+#ifndef TEST
+NOK
+#else
+OK
+#endif
+#ifndef B
+OK
+#endif
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "OK\nOK");
+}
+
+#[test]
+fn test_if_defined() {
+    let res = parse_string(r#"
+#define A 0
+#if defined(A) && (!defined B)
+OK
+#endif
+#if defined A && defined(B)
+NOK
+#elif defined( A )
+OK
+#endif
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "OK\nOK");
+}
+
+#[test]
+fn test_nested_if_in_skipped_branch() {
+    let res = parse_string(r#"
+#if false
+#ifdef A
+NOK
+#else
+NOK
+#endif
+NOK
+#else
+OK
+#endif
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "OK");
+}
+
+#[test]
+fn test_elif_after_taken_branch() {
+    let res = parse_string(r#"
+#if true
+OK
+#elif true
+NOK
+#endif
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "OK");
+}
+
+#[test]
+fn test_error() {
+    let res = parse_string(r#"
+#define WORKGROUP_SIZE 512
+#if WORKGROUP_SIZE > 256
+#error workgroup size too big
+#endif
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    );
+    match res.as_ref().map_err(|err| (err.kind(), err.location())) {
+        Err((ErrorKind::UserError(message), Some(SourceLocation { file: None, line: 4, column: 1 }))) => {
+            assert_eq!(message, "workgroup size too big");
+        },
+        _ => panic!("Expected UserError")
+    }
+}
+
+#[test]
+fn test_error_in_skipped_branch() {
+    let res = parse_string(r#"
+#if false
+#error unreachable
+#endif
+OK
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "OK");
+}
+
+#[test]
+fn test_warning() {
+    let res = Preprocessor::builder()
+        .base_dir(".")
+        .build()
+        .process_str(r#"
+#warning first
+OK
+#warning second
+"#)
+        .unwrap();
+    assert_eq!(res.output.trim(), "OK");
+    assert_eq!(res.warnings.iter().map(|w| (w.message.as_str(), w.location.line)).collect::<Vec<_>>(), [("first", 2), ("second", 4)]);
+
+    let res = Preprocessor::with_includes([("lib.txt", "\n  #warning careful")])
+        .build()
+        .process_str("#include \"lib.txt\"")
+        .unwrap();
+    assert_eq!(res.warnings[0].to_string(), "careful (at lib.txt:2:3)");
+    assert_eq!(res.warnings[0].include_stack, [SourceLocation { file: None, line: 1, column: 1 }]);
+}
+
+#[test]
+fn test_builtins() {
+    let res = parse_string(r#"
+__LINE__ __INCLUDE_LEVEL__
+#include "builtins.txt"
+"#,
+        std::env::current_dir().unwrap().join("tests"),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res, "\n2 0\n\"builtins.txt\" 1 1\n");
+}
+
+#[test]
+fn test_counter() {
+    let res = parse_string(r#"
+#define VAR(name) let name = __COUNTER__;
+VAR(a) VAR(b)
+__COUNTER__
+"#,
+        std::env::current_dir().unwrap(),
+        std::iter::empty()
+    ).unwrap();
+    assert_eq!(res.trim(), "let a = 0;  let b = 1;\n2");
+}
+
+#[test]
+fn test_preprocessor() {
+    let preprocessor = Preprocessor::builder()
+        .base_dir(std::env::current_dir().unwrap().join("tests"))
+        .include_dir(std::env::current_dir().unwrap().join("tests/include"))
+        .parameters(["hello", "world"])
+        .build();
+
+    let res = preprocessor.process_file("tests/test_with_param.txt").unwrap();
+    assert_eq!(res.output.trim(), "hello world");
+
+    // Macros of an earlier template aren't visible in the next
+    let res = preprocessor.process_str("#define A 1\n#param B\n#param C\nA B C").unwrap();
+    assert_eq!(res.output, "1 hello world");
+    let res = preprocessor.process_str("#include <shared.txt>\n#param B\n#param C\nA").unwrap();
+    assert_eq!(res.output, "Shared\nA");
+}
+
+#[test]
+fn test_predefined_macros() {
+    let preprocessor = Preprocessor::builder()
+        .base_dir(std::env::current_dir().unwrap().join("tests"))
+        .define("MAX_LIGHTS", "16")
+        .define_fn("LIST", ["first", "..."], "first: __VA_ARGS__")
+        .build();
+
+    let res = preprocessor.process_str(r#"
+#include "predefined.txt"
+LIST(a, b, c)
+#undef MAX_LIGHTS
+MAX_LIGHTS
+"#).unwrap();
+    assert_eq!(res.output.trim(), "lights: 16\na: b, c\nMAX_LIGHTS");
+
+    // `#undef` only affects the template it is used in
+    let res = preprocessor.process_str("MAX_LIGHTS").unwrap();
+    assert_eq!(res.output, "16");
+}
+
+#[test]
+fn test_invalid_predefined_macros() {
+    let res = Preprocessor::builder().define("MAX LIGHTS", "16").build().process_str("");
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::InvalidMacro(name)) if name == "MAX LIGHTS"));
+
+    let res = Preprocessor::builder().define("", "16").build().process_str("");
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::MissingMacroName("define"))));
+
+    let res = Preprocessor::builder().define_fn("F", ["1"], "").build().process_str("");
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::InvalidParameterName(name)) if name == "1"));
+
+    let res = Preprocessor::builder().define_fn("F", ["...", "a"], "").build().process_str("");
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::InvalidParameterName(name)) if name == "..."));
+}
+
+#[test]
+fn test_preprocessor_max_include_depth() {
+    let res = Preprocessor::builder()
+        .base_dir(std::env::current_dir().unwrap().join("tests"))
+        .max_include_depth(0)
+        .build()
+        .process_str(r#"#include "test.txt""#);
+    assert!(matches!(res.as_ref().map_err(Error::kind), Err(ErrorKind::IncludeDepthExceeded(0, _))));
+}
+
+#[cfg(feature = "vfs")]
+#[test]
+fn test_vfs_include_relative_to_including_file() {
+    use ppx_impl::parse_vfs;
+
+    let root: vfs::VfsPath = vfs::MemoryFS::new().into();
+    root.join("src/lib").unwrap().create_dir_all().unwrap();
+    root.join("src/main.txt").unwrap()
+        .create_file().unwrap()
+        .write_all(b"#include \"lib/a.txt\"").unwrap();
+    root.join("src/lib/a.txt").unwrap()
+        .create_file().unwrap()
+        .write_all(b"#include \"b.txt\"").unwrap();
+    root.join("src/lib/b.txt").unwrap()
+        .create_file().unwrap()
+        .write_all(b"B").unwrap();
+
+    let result = parse_vfs(root.join("src/main.txt").unwrap(), root, std::iter::empty()).unwrap();
+    assert_eq!(result, "B");
+}
+
+#[cfg(feature = "vfs")]
+#[test]
+fn test_vfs_pragma_once_canonical_path() {
+    use ppx_impl::parse_string_vfs;
+
+    let root: vfs::VfsPath = vfs::MemoryFS::new().into();
+    root.join("sub").unwrap().create_dir().unwrap();
+    root.join("once.txt").unwrap()
+        .create_file().unwrap()
+        .write_all(b"#pragma once\nONCE").unwrap();
+
+    let result = parse_string_vfs("#include \"once.txt\"\n#include \"./sub/../once.txt\"", root, std::iter::empty()).unwrap();
+    assert_eq!(result.trim(), "ONCE");
+}
+
+#[cfg(feature = "vfs")]
+#[test]
+fn test_vfs_sandboxed_includes() {
+    let root: vfs::VfsPath = vfs::MemoryFS::new().into();
+    root.join("templates").unwrap().create_dir().unwrap();
+    root.join("templates/a.txt").unwrap().create_file().unwrap().write_all(b"a").unwrap();
+    root.join("secret.txt").unwrap().create_file().unwrap().write_all(b"secret").unwrap();
+
+    let preprocessor = Preprocessor::with_loader(root.clone())
+        .base_dir(root.join("templates").unwrap())
+        .sandboxed(true)
+        .build();
+
+    assert_eq!(preprocessor.process_str("#include \"a.txt\"").unwrap().output, "a");
+    match preprocessor.process_str("#include \"../secret.txt\"").as_ref().map_err(Error::kind) {
+        Err(ErrorKind::IncludeOutsideRoot(path)) => assert_eq!(path, "../secret.txt"),
+        res => panic!("Expected IncludeOutsideRoot error, got {res:?}"),
+    }
+}
+
 #[cfg(feature = "vfs")]
 #[test]
 fn test_feature_vfs() {
@@ -1,5 +1,6 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
-#![cfg_attr(feature = "nightly", feature(proc_macro_tracked_path))]
+#![cfg_attr(feature = "nightly", feature(proc_macro_tracked_path, proc_macro_diagnostic))]
+#![allow(clippy::needless_return)]
 
 use std::path::PathBuf;
 
@@ -14,6 +15,18 @@ struct Args {
     file_path: String,
     base_path: String,
     params: Vec<String>,
+    /// In-memory files that are included instead of files on disk
+    includes: Vec<(String, String)>,
+}
+
+fn string_lit(expr: &Expr, what: &str) -> String {
+    match expr {
+        Expr::Lit(lit) => match &lit.lit {
+            syn::Lit::Str(s) => s.value(),
+            _ => panic!("Expected string literal in {}", what),
+        },
+        _ => panic!("Expected string literal in {}", what),
+    }
 }
 
 impl Parse for Args {
@@ -30,19 +43,35 @@ impl Parse for Args {
             }
         }
 
+        let mut includes: Option<ExprArray> = None;
+        if input.peek(Token![,]) {
+            input.parse::<Token![,]>()?;
+            if !input.is_empty() {
+                includes = Some(input.parse()?);
+            }
+        }
+
         let params = params
             .map(|params| {
                 params.elems
-                    .into_iter()
+                    .iter()
+                    .map(|elem| string_lit(elem, "params"))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let includes = includes
+            .map(|includes| {
+                includes.elems
+                    .iter()
                     .map(|elem| match elem {
-                        Expr::Lit(lit) => match &lit.lit {
-                            syn::Lit::Str(s) => s.value(),
-                            _ => panic!("Expected string literal in params"),
+                        Expr::Tuple(tuple) if tuple.elems.len() == 2 => {
+                            (string_lit(&tuple.elems[0], "includes"), string_lit(&tuple.elems[1], "includes"))
                         },
-                        _ => panic!("Expected string literal in params")
+                        _ => panic!("Expected (path, contents) tuple in includes"),
                     }).collect::<Vec<_>>()
             })
-            .unwrap_or(Vec::new());
+            .unwrap_or_default();
 
         if !input.is_empty() {
             if input.peek(Token![,]) {
@@ -58,7 +87,8 @@ impl Parse for Args {
         Ok(Args {
             file_path: file_path.value(),
             base_path: base_path.value(),
-            params: params
+            params,
+            includes,
         })
     }
 }
@@ -74,6 +104,9 @@ impl Parse for Args {
 #[proc_macro]
 pub fn include_ppx(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let args = syn::parse_macro_input!(input as Args);
+    if !args.includes.is_empty() {
+        panic!("In-memory includes are only supported by include_ppx_string!");
+    }
 
     let source_path = PathBuf::from(Span::call_site().file());
     let base_path = source_path.parent().unwrap();
@@ -87,10 +120,15 @@ pub fn include_ppx(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         proc_macro::tracked::path(path.to_str().expect("File path was not UTF-8 encoded"));
     }
 
-    let output = ppx::parse(file_path, base_path, args.params.iter().map(|s| s.as_str())).unwrap();
-    let output = LitStr::new(&output, Span::call_site().into());
+    let output = ppx::Preprocessor::builder()
+        .base_dir(base_path)
+        .parameters(args.params)
+        .build()
+        .process_file(file_path)
+        .unwrap_or_else(|err| panic!("{err}"));
+    let lit = LitStr::new(&output.output, Span::call_site().into());
 
-    return output.to_token_stream().into();
+    return with_warnings(lit, &output.warnings);
 }
 
 #[cfg(feature = "nightly")]
@@ -107,6 +145,9 @@ fn list_files_recursive(dir: impl AsRef<std::path::Path>, out: &mut Vec<PathBuf>
 
 /// Parse a macro at compile time from a string.
 ///
+/// An optional fourth argument lists `(path, contents)` pairs of files that are
+/// included from memory instead of from the base path, so no files are read.
+///
 /// # Example
 ///
 /// ```rust
@@ -115,6 +156,10 @@ fn list_files_recursive(dir: impl AsRef<std::path::Path>, out: &mut Vec<PathBuf>
 ///     include_ppx_string!("#define A Hello\nA", ".", []),
 ///     "Hello"
 /// );
+/// assert_eq!(
+///     include_ppx_string!("#include \"lib/a.txt\" world", ".", [], [("lib/a.txt", "#param A\nHello A")]),
+///     "Hello world"
+/// );
 /// ```
 #[proc_macro]
 pub fn include_ppx_string(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -126,8 +171,51 @@ pub fn include_ppx_string(input: proc_macro::TokenStream) -> proc_macro::TokenSt
     let contents = args.file_path;
     let base_path = base_path.join(args.base_path);
 
-    let output = ppx::parse_string(&contents, base_path, args.params.iter().map(|s| s.as_str())).unwrap();
-    let output = LitStr::new(&output, Span::call_site().into());
+    let output = if args.includes.is_empty() {
+        ppx::Preprocessor::builder()
+            .base_dir(base_path)
+            .parameters(args.params)
+            .build()
+            .process_str(&contents)
+            .unwrap_or_else(|err| panic!("{err}"))
+    } else {
+        ppx::Preprocessor::with_includes(args.includes)
+            .parameters(args.params)
+            .build()
+            .process_str(&contents)
+            .unwrap_or_else(|err| panic!("{err}"))
+    };
+    let lit = LitStr::new(&output.output, Span::call_site().into());
+
+    return with_warnings(lit, &output.warnings);
+}
 
-    return output.to_token_stream().into();
+/// Emits the `#warning`s of a template as compiler warnings.
+///
+/// On nightly these are proper diagnostics. On stable, the expansion is wrapped
+/// in a block that uses a deprecated item per warning, which makes rustc print
+/// the warning message as a deprecation note.
+fn with_warnings(expr: impl ToTokens, warnings: &[ppx::Warning]) -> proc_macro::TokenStream {
+    #[cfg(feature = "nightly")] {
+        for warning in warnings {
+            Span::call_site().warning(warning.to_string()).emit();
+        }
+        return expr.to_token_stream().into();
+    }
+    #[cfg(not(feature = "nightly"))] {
+        if warnings.is_empty() {
+            return expr.to_token_stream().into();
+        }
+
+        let notes = warnings.iter().map(|warning| warning.to_string());
+        return quote::quote! {{
+            #({
+                #[deprecated(note = #notes)]
+                #[allow(non_upper_case_globals)]
+                const ppx_warning: () = ();
+                let _ = ppx_warning;
+            })*
+            #expr
+        }}.into();
+    }
 }
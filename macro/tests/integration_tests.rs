@@ -3,3 +3,14 @@ fn test_macro() {
     let result = ppx_macros::include_ppx_string!("#param A\nA", ".", ["Hello world!"]);
     assert_eq!(result, "Hello world!");
 }
+
+#[test]
+fn test_macro_in_memory_includes() {
+    let result = ppx_macros::include_ppx_string!(
+        "#include \"lib/a.txt\" Hello",
+        "does/not/exist",
+        [],
+        [("lib/a.txt", "#param A\n#include \"b.txt\"\nA B"), ("lib/b.txt", "#define B world\n")]
+    );
+    assert_eq!(result, "Hello world");
+}
@@ -1,10 +1,11 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
-#![cfg_attr(feature = "nightly", feature(proc_macro_tracked_path))]
+#![cfg_attr(feature = "nightly", feature(proc_macro_tracked_path, proc_macro_diagnostic))]
+#![allow(clippy::needless_return)]
 
 use std::path::PathBuf;
 
 use proc_macro::Span;
-use quote::quote;
+use quote::{quote, ToTokens};
 use syn::parse::Parse;
 use syn::{Expr, ExprArray, LitStr, Token};
 
@@ -49,7 +50,7 @@ impl Parse for Args {
                         _ => panic!("Expected string literal in params")
                     }).collect::<Vec<_>>()
             })
-            .unwrap_or(Vec::new());
+            .unwrap_or_default();
 
         if !input.is_empty() {
             if input.peek(Token![,]) {
@@ -65,7 +66,7 @@ impl Parse for Args {
         Ok(Args {
             contents_or_path: contents_or_path.value(),
             base_path: base_path.value(),
-            params: params,
+            params,
             label: label.map(|l| l.value())
         })
     }
@@ -88,20 +89,21 @@ pub fn include_wgsl_template(input: proc_macro::TokenStream) -> proc_macro::Toke
         proc_macro::tracked::path(path.to_str().expect("File path was not UTF-8 encoded"));
     }
 
-    let output = match ppx::parse(&file_path, base_path, args.params.iter().map(|s| s.as_str())) {
+    let output = match ppx::Preprocessor::builder().base_dir(base_path).parameters(args.params).build().process_file(&file_path) {
         Ok(out) => out,
         Err(err) => panic!("{}", err),
     };
-    let output = LitStr::new(&output, Span::call_site().into());
+    let warnings = output.warnings;
+    let output = LitStr::new(&output.output, Span::call_site().into());
 
     let label = LitStr::new(&args.label.unwrap_or(file_path.to_str().unwrap().to_string()), Span::call_site().into());
 
-    return quote! {
+    return with_warnings(quote! {
         ::wgpu::ShaderModuleDescriptor {
             label: Some(#label),
             source: ::wgpu::ShaderSource::Wgsl(#output.into()),
         }
-    }.into();
+    }, &warnings);
 }
 
 #[cfg(feature = "nightly")]
@@ -126,15 +128,51 @@ pub fn include_wgsl_template_string(input: proc_macro::TokenStream) -> proc_macr
     let contents = args.contents_or_path;
     let base_path = base_path.join(args.base_path);
 
-    let output = ppx::parse_string(&contents, base_path, args.params.iter().map(|s| s.as_str())).unwrap();
-    let output = LitStr::new(&output, Span::call_site().into());
+    let output = ppx::Preprocessor::builder()
+        .base_dir(base_path)
+        .parameters(args.params)
+        .build()
+        .process_str(&contents)
+        .unwrap_or_else(|err| panic!("{err}"));
+    let warnings = output.warnings;
+    let output = LitStr::new(&output.output, Span::call_site().into());
 
     let label = LitStr::new(&args.label.unwrap_or(contents[0..std::cmp::min(contents.len(), 25)].to_string()), Span::call_site().into());
 
-    return quote! {
+    return with_warnings(quote! {
         ::wgpu::ShaderModuleDescriptor {
             label: Some(#label),
             source: ::wgpu::ShaderSource::Wgsl(#output.into()),
         }
-    }.into();
+    }, &warnings);
+}
+
+/// Emits the `#warning`s of a template as compiler warnings.
+///
+/// On nightly these are proper diagnostics. On stable, the expansion is wrapped
+/// in a block that uses a deprecated item per warning, which makes rustc print
+/// the warning message as a deprecation note.
+fn with_warnings(expr: impl ToTokens, warnings: &[ppx::Warning]) -> proc_macro::TokenStream {
+    #[cfg(feature = "nightly")] {
+        for warning in warnings {
+            Span::call_site().warning(warning.to_string()).emit();
+        }
+        return expr.to_token_stream().into();
+    }
+    #[cfg(not(feature = "nightly"))] {
+        if warnings.is_empty() {
+            return expr.to_token_stream().into();
+        }
+
+        let notes = warnings.iter().map(|warning| warning.to_string());
+        return quote! {{
+            #({
+                #[deprecated(note = #notes)]
+                #[allow(non_upper_case_globals)]
+                const wgsl_template_warning: () = ();
+                let _ = wgsl_template_warning;
+            })*
+            #expr
+        }}.into();
+    }
 }